@@ -0,0 +1,120 @@
+//! Throughput of `query_state` lookups under concurrent polling, with the scheduler publishing updates meanwhile.
+//!
+//! Compares the sharded `JobRegistry` against one mutex around the whole job table, as the server had before.
+//! Run with `cargo bench --bench registry`.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use filecoin_webapi::auth::ClientId;
+use filecoin_webapi::jobs::JobOutput;
+use filecoin_webapi::polling::{JobToken, PollingState};
+use filecoin_webapi::registry::{JobRegistry, Snapshot};
+
+const JOBS: u64 = 1000;
+const THREADS: &[usize] = &[1, 2, 4, 8, 16];
+const RUN_TIME: Duration = Duration::from_secs(2);
+
+trait Table: Send + Sync + 'static {
+    fn get(&self, token: JobToken, client: &ClientId) -> PollingState;
+    fn update(&self, token: JobToken, owner: &Option<String>);
+}
+
+impl Table for JobRegistry {
+    fn get(&self, token: JobToken, client: &ClientId) -> PollingState {
+        JobRegistry::get(self, token, client)
+    }
+
+    fn update(&self, token: JobToken, owner: &Option<String>) {
+        self.publish(token, owner, Snapshot::Done(JobOutput::Value(json!("done"))));
+    }
+}
+
+/// the job table behind a single lock
+struct LockedTable(Mutex<HashMap<JobToken, (Option<String>, PollingState)>>);
+
+impl Table for LockedTable {
+    fn get(&self, token: JobToken, client: &ClientId) -> PollingState {
+        match self.0.lock().unwrap().get(&token) {
+            Some((owner, state)) if client.can_access(owner) => state.clone(),
+            _ => PollingState::Expired,
+        }
+    }
+
+    fn update(&self, token: JobToken, owner: &Option<String>) {
+        let state = PollingState::Done(json!("done"));
+        self.0.lock().unwrap().insert(token, (owner.clone(), state));
+    }
+}
+
+fn token(n: u64) -> JobToken {
+    JobToken::try_from(format!("{:032x}", n.wrapping_mul(0x9e37_79b9_7f4a_7c15))).unwrap()
+}
+
+/// lookups per second of `threads` pollers, while one thread keeps updating jobs
+fn run<T: Table>(table: Arc<T>, threads: usize) -> f64 {
+    let owner = Some("bench".to_owned());
+    for n in 0..JOBS {
+        table.update(token(n), &owner);
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let lookups = Arc::new(AtomicU64::new(0));
+
+    let scheduler = {
+        let (table, stop) = (table.clone(), stop.clone());
+        thread::spawn(move || {
+            let mut n = 0;
+            while !stop.load(Ordering::Relaxed) {
+                table.update(token(n % JOBS), &owner);
+                n += 1;
+            }
+        })
+    };
+
+    let pollers: Vec<_> = (0..threads)
+        .map(|i| {
+            let (table, stop, lookups) = (table.clone(), stop.clone(), lookups.clone());
+            thread::spawn(move || {
+                let client = ClientId {
+                    name: "bench".to_owned(),
+                    admin: false,
+                };
+
+                let mut n = i as u64;
+                let mut count = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    table.get(token(n % JOBS), &client);
+                    n += 7;
+                    count += 1;
+                }
+                lookups.fetch_add(count, Ordering::Relaxed);
+            })
+        })
+        .collect();
+
+    let start = Instant::now();
+    thread::sleep(RUN_TIME);
+    stop.store(true, Ordering::Relaxed);
+    for poller in pollers {
+        poller.join().unwrap();
+    }
+    scheduler.join().unwrap();
+
+    lookups.load(Ordering::Relaxed) as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    println!("{:>8} {:>16} {:>16}", "threads", "locked/s", "registry/s");
+    for &threads in THREADS {
+        let locked = run(Arc::new(LockedTable(Mutex::new(HashMap::new()))), threads);
+        let registry = run(Arc::new(JobRegistry::new()), threads);
+        println!("{:>8} {:>16.0} {:>16.0}", threads, locked, registry);
+    }
+}
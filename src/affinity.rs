@@ -0,0 +1,156 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::Path;
+
+use log::{info, warn};
+
+use crate::config::CONFIG;
+use crate::polling::{JobKind, JobToken};
+
+const CPU_DIR: &str = "/sys/devices/system/cpu";
+const NODE_DIR: &str = "/sys/devices/system/node";
+
+/// Hands out disjoint core sets to PC1 jobs, each set within one L3 cache or NUMA node
+pub struct CoreAllocator {
+    /// cores sharing a L3 cache, or a NUMA node where the cache topology is unknown
+    groups: Vec<Vec<usize>>,
+    used: HashMap<JobToken, Vec<usize>>,
+}
+
+impl CoreAllocator {
+    pub fn detect() -> Self {
+        let groups = cache_groups()
+            .or_else(|_| numa_nodes())
+            .or_else(|_| read_cpu_list(&Path::new(CPU_DIR).join("online")).map(|x| vec![x]))
+            .unwrap_or_else(|e| {
+                warn!("read cpu topology failed: {:?}", e);
+                vec![]
+            });
+
+        info!("cpu groups: {:?}", groups);
+        Self {
+            groups,
+            used: HashMap::new(),
+        }
+    }
+
+    /// cores per PC1 job, by default every PC1 job the limit allows gets an equal share within one cache group
+    fn cores_per_job(&self) -> usize {
+        if CONFIG.pc1_cores > 0 {
+            return CONFIG.pc1_cores;
+        }
+
+        let smallest = self.groups.iter().map(|x| x.len()).min().unwrap_or(0);
+        let total: usize = self.groups.iter().map(|x| x.len()).sum();
+        let jobs = CONFIG.max_jobs(JobKind::SealPreCommitPhase1).max(1);
+        (total / jobs).max(1).min(smallest)
+    }
+
+    /// false when the topology is unknown, PC1 jobs then run unpinned
+    pub fn is_enabled(&self) -> bool {
+        self.cores_per_job() > 0
+    }
+
+    /// reserve cores for the job, `None` if no group has enough free cores left
+    pub fn allocate(&mut self, token: JobToken) -> Option<Vec<usize>> {
+        let n = self.cores_per_job();
+        if n == 0 {
+            return None;
+        }
+
+        let used: HashSet<usize> = self.used.values().flatten().copied().collect();
+
+        // the tightest group that fits, so larger ones stay free for later jobs
+        let cores: Vec<usize> = self
+            .groups
+            .iter()
+            .map(|group| group.iter().filter(|x| !used.contains(x)).copied().collect::<Vec<_>>())
+            .filter(|free| free.len() >= n)
+            .min_by_key(|free| free.len())?
+            .into_iter()
+            .take(n)
+            .collect();
+
+        self.used.insert(token, cores.clone());
+        Some(cores)
+    }
+
+    pub fn release(&mut self, token: JobToken) {
+        self.used.remove(&token);
+    }
+}
+
+/// pin the calling process, called in the forked worker before exec
+pub fn set_affinity(cores: &[usize]) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        for core in cores {
+            libc::CPU_SET(*core, &mut set);
+        }
+
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+fn cache_groups() -> io::Result<Vec<Vec<usize>>> {
+    let online = read_cpu_list(&Path::new(CPU_DIR).join("online"))?;
+
+    let mut groups: Vec<Vec<usize>> = vec![];
+    for cpu in online.iter() {
+        if groups.iter().any(|x| x.contains(cpu)) {
+            continue;
+        }
+
+        let path = Path::new(CPU_DIR).join(format!("cpu{}/cache/index3/shared_cpu_list", cpu));
+        let shared = read_cpu_list(&path)?;
+        groups.push(shared.into_iter().filter(|x| online.contains(x)).collect());
+    }
+
+    Ok(groups)
+}
+
+fn numa_nodes() -> io::Result<Vec<Vec<usize>>> {
+    let mut nodes = vec![];
+    for entry in fs::read_dir(NODE_DIR)? {
+        let path = entry?.path();
+        let is_node = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .map(|x| x.starts_with("node"))
+            .unwrap_or(false);
+
+        if is_node {
+            let cpus = read_cpu_list(&path.join("cpulist"))?;
+            if !cpus.is_empty() {
+                nodes.push(cpus);
+            }
+        }
+    }
+
+    Ok(nodes)
+}
+
+/// parse a kernel cpu list such as `0-3,8-11`
+fn read_cpu_list(path: &Path) -> io::Result<Vec<usize>> {
+    let content = fs::read_to_string(path)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, content.trim()));
+
+    let mut cpus = vec![];
+    for item in content.trim().split(',').filter(|x| !x.is_empty()) {
+        let mut range = item.splitn(2, '-').map(|x| x.parse::<usize>());
+        match (range.next(), range.next()) {
+            (Some(Ok(cpu)), None) => cpus.push(cpu),
+            (Some(Ok(first)), Some(Ok(last))) => cpus.extend(first..=last),
+            _ => return Err(invalid()),
+        }
+    }
+
+    Ok(cpus)
+}
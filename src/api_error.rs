@@ -0,0 +1,183 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+
+use crate::polling::{PollingError, PollingState};
+
+/// Stable, machine readable kind of an error
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// the request can not be carried out as given
+    InvalidInput,
+    /// a file or directory named in the request does not exist
+    FileNotFound,
+    /// the job or sector does not exist
+    NotFound,
+    InsufficientResources,
+    /// the proofs library returned an error
+    ProofFailed,
+    Cancelled,
+    /// the job is in a state the operation does not apply to, e.g. it has not finished yet
+    Conflict,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidInput => StatusCode::BAD_REQUEST,
+            ErrorCode::FileNotFound | ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::InsufficientResources => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Cancelled | ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::ProofFailed | ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Error of an operation, as the clients get it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// what led to `message`, outermost first
+    #[serde(default)]
+    pub causes: Vec<String>,
+}
+
+impl ApiError {
+    pub fn new<M: Into<String>>(code: ErrorCode, message: M) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            causes: vec![],
+        }
+    }
+
+    /// a file operation failed for a reason other than a bad request, e.g. a full disk or an I/O error,
+    /// so running the same request again may succeed
+    pub fn is_transient(&self) -> bool {
+        self.code == ErrorCode::Internal
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)?;
+        for cause in self.causes.iter() {
+            write!(f, ": {}", cause)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl StdError for ApiError {}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.code.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self)
+    }
+}
+
+fn io_code(kind: io::ErrorKind) -> ErrorCode {
+    match kind {
+        io::ErrorKind::NotFound => ErrorCode::FileNotFound,
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => ErrorCode::InvalidInput,
+        _ => ErrorCode::Internal,
+    }
+}
+
+/// errors of the proofs are `ProofFailed`, unless they come from a file operation
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        let code = e
+            .chain()
+            .find_map(|x| x.downcast_ref::<io::Error>())
+            .map(|x| io_code(x.kind()))
+            .unwrap_or(ErrorCode::ProofFailed);
+
+        Self {
+            code,
+            message: e.to_string(),
+            causes: e.chain().skip(1).map(|x| x.to_string()).collect(),
+        }
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(e: io::Error) -> Self {
+        let mut causes = vec![];
+        let mut source = e.source();
+        while let Some(cause) = source {
+            causes.push(cause.to_string());
+            source = cause.source();
+        }
+
+        Self {
+            code: io_code(e.kind()),
+            message: e.to_string(),
+            causes,
+        }
+    }
+}
+
+impl From<BlockingError<io::Error>> for ApiError {
+    fn from(e: BlockingError<io::Error>) -> Self {
+        match e {
+            BlockingError::Error(e) => e.into(),
+            BlockingError::Canceled => ApiError::new(ErrorCode::Internal, "blocking operation was cancelled"),
+        }
+    }
+}
+
+impl From<PollingError> for ApiError {
+    fn from(e: PollingError) -> Self {
+        match e {
+            PollingError::NotExist => ApiError::new(ErrorCode::NotFound, "job does not exist"),
+            PollingError::Disconnected => ApiError::new(ErrorCode::Internal, "lost the worker of the job"),
+            PollingError::Interrupted => ApiError::new(ErrorCode::Internal, "the server restarted during the job"),
+            PollingError::Cancelled => ApiError::new(ErrorCode::Cancelled, "the job was cancelled"),
+            PollingError::NotFinished => ApiError::new(ErrorCode::Conflict, "the job is not finished"),
+            PollingError::InsufficientResources(reason) => ApiError::new(ErrorCode::InsufficientResources, reason),
+            PollingError::WorkerFailed(reason) => ApiError {
+                causes: vec![reason],
+                ..ApiError::new(ErrorCode::Internal, "the worker failed")
+            },
+            PollingError::StepFailed(e) => e,
+        }
+    }
+}
+
+/// answer of an operation: `{"Ok": ..}` with 200, or `{"Err": ApiError}` with the status of the error
+pub fn respond<T: Serialize>(r: Result<T, ApiError>) -> HttpResponse {
+    let status = match &r {
+        Ok(_) => StatusCode::OK,
+        Err(e) => e.code.status(),
+    };
+
+    HttpResponse::build(status).json(r)
+}
+
+/// `respond` of an operation that failed before it started
+impl From<ApiError> for HttpResponse {
+    fn from(e: ApiError) -> Self {
+        respond::<()>(Err(e))
+    }
+}
+
+/// answer of a job submission or query: the state with 200, or the `ApiError` of `PollingState::Error` with its status
+pub fn respond_state(state: PollingState) -> HttpResponse {
+    match state.into_result() {
+        Ok(state) => HttpResponse::Ok().json(state),
+        Err(e) => e.error_response(),
+    }
+}
@@ -0,0 +1,55 @@
+use actix_web::dev::Payload;
+use actix_web::error::ErrorUnauthorized;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{Error, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+
+use crate::config::CONFIG;
+
+/// Identity of the client sending a request, jobs are only visible to the client that submitted them
+#[derive(Clone, Debug)]
+pub struct ClientId {
+    pub name: String,
+    /// may query and cancel the jobs of every client
+    pub admin: bool,
+}
+
+impl ClientId {
+    fn from_http_request(req: &HttpRequest) -> Result<Self, Error> {
+        let name = if CONFIG.api_keys.is_empty() {
+            // without api keys clients are told apart by their address
+            req.peer_addr().map(|x| x.ip().to_string()).unwrap_or_default()
+        } else {
+            let key = req
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|x| x.to_str().ok())
+                .map(|x| x.trim_start_matches("Bearer ").trim());
+
+            match key.and_then(|x| CONFIG.api_keys.get(x)) {
+                Some(name) => name.clone(),
+                None => return Err(ErrorUnauthorized("missing or unknown api key")),
+            }
+        };
+
+        Ok(Self {
+            admin: CONFIG.admins.contains(&name),
+            name,
+        })
+    }
+
+    /// jobs restored from journals without an owner are only visible to admins
+    pub fn can_access(&self, owner: &Option<String>) -> bool {
+        self.admin || owner.as_ref() == Some(&self.name)
+    }
+}
+
+impl FromRequest for ClientId {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Self::from_http_request(req))
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use log::warn;
+use serde_json::Value;
+
+use crate::polling::JobKind;
+
+lazy_static! {
+    pub static ref CONFIG: ServConfig = ServConfig::from_env();
+}
+
+#[derive(Clone, Debug)]
+pub struct ServConfig {
+    /// directory for the job journal and other persistent server state
+    pub state_dir: PathBuf,
+    /// finished results not acknowledged within this time are dropped
+    pub result_ttl: Duration,
+    /// tombstones of dropped results are kept this long, then the job looks like it never existed
+    pub tombstone_ttl: Duration,
+    /// how often expired results are swept
+    pub sweep_interval: Duration,
+    /// per job kind concurrency limits
+    pub max_jobs: HashMap<JobKind, usize>,
+    /// limit of all running jobs, 0 for no limit
+    pub max_total_jobs: usize,
+    /// slots of `max_total_jobs` only PoSt jobs may use
+    pub reserved_post_slots: usize,
+    /// retries of a failed job callback before giving up
+    pub callback_retries: u32,
+    /// timeout of one callback request
+    pub callback_timeout: Duration,
+    /// longest time `/sys/wait_job` holds a request
+    pub max_wait: Duration,
+    /// how often `/sys/watch_job` checks the progress of a running job
+    pub watch_interval: Duration,
+    /// api key to client name, clients are identified by address when empty
+    pub api_keys: HashMap<String, String>,
+    /// clients that may access the jobs of every client
+    pub admins: HashSet<String>,
+    /// hold back jobs whose estimated memory and disk needs do not fit
+    pub admission: bool,
+    /// pin each PC1 worker to its own set of cores
+    pub pin_pc1: bool,
+    /// cores per PC1 job, 0 to share the cores evenly among the PC1 jobs allowed, within one L3 cache each
+    pub pc1_cores: usize,
+    /// per job kind automatic retries after a transient failure
+    pub retries: HashMap<JobKind, usize>,
+    /// wait before the first automatic retry, doubled for every further one
+    pub retry_backoff: Duration,
+    /// bytes of a result above which it is stored as a file and downloaded by reference
+    pub result_inline_limit: u64,
+}
+
+impl ServConfig {
+    pub fn from_env() -> Self {
+        Self {
+            state_dir: env::var("WEBAPI_STATE_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| PathBuf::from("/var/tmp/filecoin-webapi/")),
+            result_ttl: env_secs("WEBAPI_RESULT_TTL", 24 * 3600),
+            tombstone_ttl: env_secs("WEBAPI_TOMBSTONE_TTL", 7 * 24 * 3600),
+            sweep_interval: env_secs("WEBAPI_SWEEP_INTERVAL", 60),
+            max_jobs: env_kind_map("WEBAPI_MAX_JOBS"),
+            max_total_jobs: env_parse("WEBAPI_MAX_TOTAL_JOBS", 0),
+            reserved_post_slots: env_parse("WEBAPI_RESERVED_POST_SLOTS", 0),
+            callback_retries: env_parse("WEBAPI_CALLBACK_RETRIES", 5),
+            callback_timeout: env_secs("WEBAPI_CALLBACK_TIMEOUT", 30),
+            max_wait: env_secs("WEBAPI_MAX_WAIT", 600),
+            watch_interval: env_secs("WEBAPI_WATCH_INTERVAL", 5),
+            api_keys: env_pairs("WEBAPI_API_KEYS")
+                .into_iter()
+                .map(|(name, key)| (key, name))
+                .collect(),
+            admins: env_list("WEBAPI_ADMINS").into_iter().collect(),
+            admission: env_parse("WEBAPI_ADMISSION", true),
+            pin_pc1: env_parse("WEBAPI_PIN_PC1", true),
+            pc1_cores: env_parse("WEBAPI_PC1_CORES", 0),
+            retries: env_kind_map("WEBAPI_RETRIES"),
+            retry_backoff: env_secs("WEBAPI_RETRY_BACKOFF", 60),
+            result_inline_limit: env_parse("WEBAPI_RESULT_INLINE_LIMIT", 1 << 20),
+        }
+    }
+
+    pub fn max_jobs(&self, kind: JobKind) -> usize {
+        self.max_jobs
+            .get(&kind)
+            .copied()
+            .unwrap_or_else(|| kind.default_max_jobs())
+    }
+
+    pub fn retries(&self, kind: JobKind) -> usize {
+        self.retries.get(&kind).copied().unwrap_or(0)
+    }
+
+    /// number of running jobs below which a job of `kind` may start
+    pub fn max_total_jobs(&self, kind: JobKind) -> usize {
+        match self.max_total_jobs {
+            0 => usize::max_value(),
+            n if kind.is_post() => n,
+            n => n.saturating_sub(self.reserved_post_slots),
+        }
+    }
+}
+
+/// parse `kind=value` pairs separated by commas, e.g. `seal_commit_phase1=2,seal_commit_phase2=1`
+fn env_kind_map(name: &str) -> HashMap<JobKind, usize> {
+    let mut map = HashMap::new();
+
+    for (key, value) in env_pairs(name) {
+        let kind = serde_json::from_value(Value::String(key.clone())).ok();
+
+        match (kind, value.parse().ok()) {
+            (Some(kind), Some(value)) => {
+                map.insert(kind, value);
+            }
+            _ => warn!("ignore invalid {} item: {}={}", name, key, value),
+        }
+    }
+
+    map
+}
+
+/// parse `key=value` pairs separated by commas
+fn env_pairs(name: &str) -> Vec<(String, String)> {
+    let mut pairs = vec![];
+
+    for item in env_list(name) {
+        let mut parts = item.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => pairs.push((key.trim().to_owned(), value.trim().to_owned())),
+            _ => warn!("ignore invalid {} item: {}", name, item),
+        }
+    }
+
+    pairs
+}
+
+/// parse a comma separated list
+fn env_list(name: &str) -> Vec<String> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+fn env_parse<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name).ok().and_then(|x| x.parse().ok()).unwrap_or(default)
+}
+
+fn env_secs(name: &str, default: u64) -> Duration {
+    Duration::from_secs(env_parse(name, default))
+}
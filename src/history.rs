@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use filecoin_proofs_api::{ProverId, SectorId};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::polling::{JobKind, JobToken};
+
+const HISTORY_FILE: &str = "jobs.history";
+
+/// window of `/sys/job_stats` when the client sets none
+const DEFAULT_WINDOW: u64 = 7 * 24 * 3600;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Done,
+    Failed,
+    Cancelled,
+}
+
+/// One run of a job, written when it ends
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryRecord {
+    pub token: JobToken,
+    pub kind: JobKind,
+    pub proof: Option<String>,
+    pub prover_id: Option<ProverId>,
+    pub sector_id: Option<SectorId>,
+    pub owner: Option<String>,
+    pub start_time: u64,
+    pub end_time: u64,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+}
+
+impl HistoryRecord {
+    fn duration(&self) -> u64 {
+        self.end_time.saturating_sub(self.start_time)
+    }
+}
+
+/// Append-only log of finished jobs, one json encoded `HistoryRecord` per line, never compacted
+pub struct History {
+    path: PathBuf,
+    file: File,
+}
+
+impl History {
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(HISTORY_FILE);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        Ok(Self { path, file })
+    }
+
+    pub fn append(&mut self, record: &HistoryRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        self.file.write_all(&line)
+    }
+
+    /// records of the jobs that ended within `from..=to`
+    pub fn read(&self, from: u64, to: u64) -> io::Result<Vec<HistoryRecord>> {
+        let reader = BufReader::new(File::open(&self.path)?);
+
+        let mut records = vec![];
+        for (n, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str::<HistoryRecord>(&line) {
+                Ok(record) if record.end_time >= from && record.end_time <= to => records.push(record),
+                Ok(_) => {}
+                Err(e) => warn!("skip broken history line {}: {:?}", n + 1, e),
+            }
+        }
+
+        Ok(records)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StatsQuery {
+    /// unix time, defaults to `to` minus `window`
+    pub from: Option<u64>,
+    /// unix time, defaults to now
+    pub to: Option<u64>,
+    /// seconds, defaults to one week
+    pub window: Option<u64>,
+}
+
+impl StatsQuery {
+    pub fn range(&self, now: u64) -> (u64, u64) {
+        let to = self.to.unwrap_or(now);
+        let from = self
+            .from
+            .unwrap_or_else(|| to.saturating_sub(self.window.unwrap_or(DEFAULT_WINDOW)));
+
+        (from, to)
+    }
+}
+
+/// Statistics of one operation and proof type, durations are in seconds of successful runs
+#[derive(Serialize, Debug)]
+pub struct JobStats {
+    pub kind: JobKind,
+    pub proof: Option<String>,
+    pub count: usize,
+    pub failures: usize,
+    pub cancelled: usize,
+    /// failures of the runs that were not cancelled
+    pub failure_rate: f64,
+    pub p50: Option<u64>,
+    pub p95: Option<u64>,
+}
+
+pub fn stats<'a, I: IntoIterator<Item = &'a HistoryRecord>>(records: I) -> Vec<JobStats> {
+    let mut groups: BTreeMap<(String, Option<String>), Vec<&HistoryRecord>> = BTreeMap::new();
+    for record in records {
+        let key = (format!("{:?}", record.kind), record.proof.clone());
+        groups.entry(key).or_default().push(record);
+    }
+
+    groups
+        .into_iter()
+        .map(|((_, proof), records)| {
+            let count = records.len();
+            let failures = records.iter().filter(|x| x.outcome == Outcome::Failed).count();
+            let cancelled = records.iter().filter(|x| x.outcome == Outcome::Cancelled).count();
+            let finished = count - cancelled;
+
+            let mut durations: Vec<u64> = records
+                .iter()
+                .filter(|x| x.outcome == Outcome::Done)
+                .map(|x| x.duration())
+                .collect();
+            durations.sort();
+
+            JobStats {
+                kind: records[0].kind,
+                proof,
+                count,
+                failures,
+                cancelled,
+                failure_rate: if finished > 0 {
+                    failures as f64 / finished as f64
+                } else {
+                    0.0
+                },
+                p50: percentile(&durations, 50),
+                p95: percentile(&durations, 95),
+            }
+        })
+        .collect()
+}
+
+/// nearest-rank percentile of sorted values
+fn percentile(sorted: &[u64], p: usize) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+
+    let rank = (sorted.len() * p + 99) / 100;
+    sorted.get(rank.saturating_sub(1)).copied()
+}
@@ -0,0 +1,850 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use filecoin_proofs_api::{post, seal, PieceInfo, RegisteredSealProof};
+use log::{info, trace, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::affinity::set_affinity;
+use crate::api_error::ApiError;
+use crate::config::CONFIG;
+use crate::polling::{JobKind, JobMeta, JobToken, PollingError, PollingState};
+use crate::post_data::*;
+use crate::resources::{Estimate, Requirements};
+use crate::seal_data::*;
+use crate::staged;
+use crate::types::WebPieceInfo;
+
+const WORKER_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// original requests of jobs below `state_dir`, kept so jobs can be run again
+const REQUEST_DIR: &str = "requests";
+
+/// results too large to keep in memory, below `state_dir`
+const RESULT_DIR: &str = "results";
+
+/// log filter of the workers, the phase and layer markers are logged by the proofs and bellperson
+const WORKER_LOG: &str = "filecoin_webapi=info,filecoin_proofs=info,storage_proofs_porep=info,bellperson=info";
+
+/// logged by the proofs before each layer of PC1 is labeled
+const LAYER_MARKER: &str = "generating layer: ";
+
+/// cache files written by PC1, besides the replica at `out_path`
+const PC1_CACHE_FILES: &[&str] = &["sc-02-data-layer-", "sc-02-data-tree-d"];
+
+/// cache files written by PC2
+const PC2_CACHE_FILES: &[&str] = &["sc-02-data-tree-c", "sc-02-data-tree-r-last", "p_aux", "t_aux"];
+
+/// log lines of the proofs that mark the start of a job phase
+const PHASE_MARKERS: &[(JobKind, &str, &str)] = &[
+    (JobKind::SealPreCommitPhase2, "tree_c", "building tree c"),
+    (JobKind::SealPreCommitPhase2, "tree_r_last", "building tree r last"),
+    (JobKind::SealCommitPhase1, "seal_commit_phase1:start", "vanilla proofs"),
+    (
+        JobKind::SealCommitPhase2,
+        "seal_commit_phase2:start",
+        "loading parameters",
+    ),
+    (JobKind::SealCommitPhase2, "snark_proof:start", "circuit synthesis"),
+    (JobKind::SealCommitPhase2, "synthesis time", "proving"),
+    (JobKind::SealCommitPhase2, "snark_proof:finish", "finishing"),
+    (JobKind::GenerateWinningPost, "vanilla_proofs:start", "vanilla proofs"),
+    (JobKind::GenerateWinningPost, "snark_proof:start", "proving"),
+    (JobKind::GenerateWindowPost, "vanilla_proofs:start", "vanilla proofs"),
+    (JobKind::GenerateWindowPost, "snark_proof:start", "proving"),
+];
+
+/// A job as submitted by a client, executed by a `worker` subprocess
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum JobRequest {
+    SealPreCommitPhase1(SealPreCommitPhase1Data),
+    SealPreCommitPhase2(SealPreCommitPhase2Data),
+    SealCommitPhase1(SealCommitPhase1Data),
+    SealCommitPhase2(SealCommitPhase2Data),
+    GetUnsealedRange(GetUnsealedRangeData),
+    GeneratePieceCommitment(GeneratePieceCommitmentData),
+    AddPiece(AddPieceData),
+    WriteAndPreprocess(WriteAndPreprocessData),
+    StageSector(StageSectorData),
+    GenerateWinningPost(GenerateWinningPostData),
+    GenerateWindowPost(GenerateWindowPostData),
+    /// sleep for the given seconds
+    TestPolling(u64),
+}
+
+impl JobRequest {
+    pub fn meta(&self) -> JobMeta {
+        let meta = match self {
+            JobRequest::SealPreCommitPhase1(data) => {
+                JobMeta::new(JobKind::SealPreCommitPhase1).with_sector(data.prover_id, data.sector_id)
+            }
+            JobRequest::SealPreCommitPhase2(_) => JobMeta::new(JobKind::SealPreCommitPhase2),
+            JobRequest::SealCommitPhase1(data) => {
+                JobMeta::new(JobKind::SealCommitPhase1).with_sector(data.prover_id, data.sector_id)
+            }
+            JobRequest::SealCommitPhase2(data) => {
+                JobMeta::new(JobKind::SealCommitPhase2).with_sector(data.prover_id, data.sector_id)
+            }
+            JobRequest::GetUnsealedRange(data) => {
+                JobMeta::new(JobKind::GetUnsealedRange).with_sector(data.prover_id, data.sector_id)
+            }
+            JobRequest::GeneratePieceCommitment(_) => JobMeta::new(JobKind::GeneratePieceCommitment),
+            JobRequest::AddPiece(_) => JobMeta::new(JobKind::AddPiece),
+            JobRequest::WriteAndPreprocess(_) => JobMeta::new(JobKind::WriteAndPreprocess),
+            JobRequest::StageSector(_) => JobMeta::new(JobKind::StageSector),
+            JobRequest::GenerateWinningPost(data) => {
+                JobMeta::new(JobKind::GenerateWinningPost).with_prover(data.prover_id)
+            }
+            JobRequest::GenerateWindowPost(data) => {
+                JobMeta::new(JobKind::GenerateWindowPost).with_prover(data.prover_id)
+            }
+            JobRequest::TestPolling(_) => JobMeta::new(JobKind::TestPolling),
+        };
+
+        meta.with_proof(self.proof())
+    }
+
+    fn proof(&self) -> Option<String> {
+        let proof = match self {
+            JobRequest::SealPreCommitPhase1(data) => data.registered_proof,
+            JobRequest::SealPreCommitPhase2(data) => data.phase1_output.registered_proof,
+            JobRequest::SealCommitPhase1(data) => data.pre_commit.registered_proof,
+            JobRequest::SealCommitPhase2(data) => data.phase1_output.registered_proof,
+            JobRequest::GetUnsealedRange(data) => data.registered_proof,
+            JobRequest::GeneratePieceCommitment(data) => data.registered_proof,
+            JobRequest::AddPiece(data) => data.registered_proof,
+            JobRequest::WriteAndPreprocess(data) => data.registered_proof,
+            JobRequest::StageSector(data) => data.registered_proof,
+            JobRequest::GenerateWinningPost(data) | JobRequest::GenerateWindowPost(data) => {
+                return data
+                    .replicas
+                    .0
+                    .first()
+                    .map(|x| format!("{:?}", x.private_replica_info.registered_proof))
+            }
+            JobRequest::TestPolling(_) => return None,
+        };
+
+        Some(format!("{:?}", proof))
+    }
+
+    /// peak memory and disk estimated from the proof type and sector size, for 32GiB sectors PC1 needs
+    /// about 64GiB memory and 11 layers on disk, C2 well over 100GiB memory
+    pub fn requirements(&self) -> Requirements {
+        match self {
+            JobRequest::SealPreCommitPhase1(data) => data.requirements(),
+            JobRequest::SealPreCommitPhase2(data) => data.requirements(),
+            JobRequest::SealCommitPhase1(data) => data.requirements(),
+            JobRequest::SealCommitPhase2(data) => data.requirements(),
+            JobRequest::GetUnsealedRange(data) => data.requirements(),
+            JobRequest::GeneratePieceCommitment(data) => data.requirements(),
+            JobRequest::AddPiece(data) => data.requirements(),
+            JobRequest::WriteAndPreprocess(data) => data.requirements(),
+            JobRequest::StageSector(data) => data.requirements(),
+            JobRequest::GenerateWinningPost(data) | JobRequest::GenerateWindowPost(data) => data.requirements(),
+            JobRequest::TestPolling(_) => Requirements::default(),
+        }
+    }
+
+    /// number of layers PC1 labels, `None` for other jobs
+    fn layers(&self) -> Option<u64> {
+        match self {
+            JobRequest::SealPreCommitPhase1(data) => Some(layers(data.registered_proof)),
+            _ => None,
+        }
+    }
+
+    /// run the job in the current process
+    pub fn execute(self) -> Value {
+        match self {
+            JobRequest::SealPreCommitPhase1(data) => {
+                let piece_infos: Vec<PieceInfo> = data.piece_infos.iter().map(|x| x.as_object()).collect();
+
+                let r = seal::seal_pre_commit_phase1(
+                    data.registered_proof,
+                    &data.cache_path,
+                    &data.in_path,
+                    &data.out_path,
+                    data.prover_id,
+                    data.sector_id,
+                    data.ticket,
+                    &piece_infos[..],
+                );
+
+                trace!("seal_pre_commit_phase1 finished: {:?}", r);
+                json!(r.map_err(ApiError::from))
+            }
+            JobRequest::SealPreCommitPhase2(data) => {
+                let r = seal::seal_pre_commit_phase2(data.phase1_output, &data.cache_path, &data.out_path);
+
+                trace!("seal_pre_commit_phase2 finished: {:?}", r);
+                json!(r.map_err(ApiError::from))
+            }
+            JobRequest::SealCommitPhase1(data) => {
+                let piece_infos: Vec<PieceInfo> = data.piece_infos.iter().map(|x| x.as_object()).collect();
+
+                let r = seal::seal_commit_phase1(
+                    &data.cache_path,
+                    &data.replica_path,
+                    data.prover_id,
+                    data.sector_id,
+                    data.ticket,
+                    data.seed,
+                    data.pre_commit,
+                    &piece_infos[..],
+                );
+
+                trace!("seal_commit_phase1 finished: {:?}", r);
+                json!(r.map_err(ApiError::from))
+            }
+            JobRequest::SealCommitPhase2(data) => {
+                let r = seal::seal_commit_phase2(data.phase1_output, data.prover_id, data.sector_id);
+
+                trace!("seal_commit_phase2 finished: {:?}", r);
+                json!(r.map_err(ApiError::from))
+            }
+            JobRequest::GetUnsealedRange(data) => {
+                let r = seal::get_unsealed_range(
+                    data.registered_proof,
+                    &data.cache_path,
+                    &data.sealed_path,
+                    &data.output_path,
+                    data.prover_id,
+                    data.sector_id,
+                    data.comm_d,
+                    data.ticket,
+                    data.offset,
+                    data.num_bytes,
+                );
+
+                trace!("get_unsealed_range finished: {:?}", r);
+                json!(r.map_err(ApiError::from))
+            }
+            JobRequest::GeneratePieceCommitment(data) => {
+                let r = File::open(&data.source).map_err(ApiError::from).and_then(|source| {
+                    seal::generate_piece_commitment(data.registered_proof, source, data.piece_size)
+                        .map_err(ApiError::from)
+                });
+
+                trace!("generate_piece_commitment finished: {:?}", r);
+                json!(r.map(WebPieceInfo::from_object))
+            }
+            JobRequest::AddPiece(data) => {
+                let r = staged::add_piece(&data);
+
+                trace!("add_piece finished: {:?}", r);
+                json!(r)
+            }
+            JobRequest::WriteAndPreprocess(data) => {
+                let r = staged::write_and_preprocess(&data);
+
+                trace!("write_and_preprocess finished: {:?}", r);
+                json!(r)
+            }
+            JobRequest::StageSector(data) => {
+                let r = staged::stage_sector(&data);
+
+                trace!("stage_sector finished: {:?}", r);
+                json!(r)
+            }
+            JobRequest::GenerateWinningPost(data) => {
+                let r = post::generate_winning_post(&data.randomness, &data.replicas.as_object(), data.prover_id);
+
+                trace!("generate_winning_post finished: {:?}", r);
+                json!(r.map_err(ApiError::from))
+            }
+            JobRequest::GenerateWindowPost(data) => {
+                let r = post::generate_window_post(&data.randomness, &data.replicas.as_object(), data.prover_id);
+
+                trace!("generate_window_post finished: {:?}", r);
+                json!(r.map_err(ApiError::from))
+            }
+            JobRequest::TestPolling(secs) => {
+                thread::sleep(Duration::from_secs(secs));
+                let r = "Ok!!!";
+
+                json!(r)
+            }
+        }
+    }
+
+    fn request_path(token: JobToken) -> PathBuf {
+        CONFIG.state_dir.join(REQUEST_DIR).join(format!("{}.json", token))
+    }
+
+    pub fn save(&self, token: JobToken) -> io::Result<()> {
+        let path = Self::request_path(token);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    pub fn load(token: JobToken) -> io::Result<Self> {
+        let reader = BufReader::new(File::open(Self::request_path(token))?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// drop the stored request once the job is gone
+    pub fn discard(token: JobToken) {
+        if let Err(e) = remove_file(&Self::request_path(token)) {
+            warn!("remove request of job {} failed: {:?}", token, e);
+        }
+    }
+
+    /// false for a job reading a `BodyPipe`, the upload it carried went with the first attempt
+    pub fn can_retry(&self) -> bool {
+        match self {
+            JobRequest::AddPiece(data) => !fs::metadata(&data.source)
+                .map(|x| x.file_type().is_fifo())
+                .unwrap_or(false),
+            _ => true,
+        }
+    }
+
+    /// length of the file the job appends to, taken before it starts so a partial piece can be cut off again
+    pub fn target_len(&self) -> Option<u64> {
+        let target = match self {
+            JobRequest::AddPiece(data) => &data.target,
+            JobRequest::WriteAndPreprocess(data) => &data.target,
+            _ => return None,
+        };
+
+        Some(fs::metadata(target).map(|x| x.len()).unwrap_or(0))
+    }
+
+    /// remove partial outputs of a job killed before it finished or failed, `target_len` is from before it started
+    pub fn cleanup(&self, target_len: Option<u64>) -> io::Result<()> {
+        match self {
+            JobRequest::SealPreCommitPhase1(data) => {
+                remove_file(Path::new(&data.out_path))?;
+                remove_cache_files(Path::new(&data.cache_path), PC1_CACHE_FILES)
+            }
+            JobRequest::SealPreCommitPhase2(data) => remove_cache_files(Path::new(&data.cache_path), PC2_CACHE_FILES),
+            JobRequest::GetUnsealedRange(data) => remove_file(Path::new(&data.output_path)),
+            JobRequest::AddPiece(data) => truncate(Path::new(&data.target), target_len),
+            JobRequest::WriteAndPreprocess(data) => truncate(Path::new(&data.target), target_len),
+            // the staged file is rewritten from the start, what is left of it is of no use
+            JobRequest::StageSector(data) => remove_file(Path::new(&data.target)),
+            // these only read their inputs, the output lives in the worker memory
+            JobRequest::SealCommitPhase1(_)
+            | JobRequest::SealCommitPhase2(_)
+            | JobRequest::GeneratePieceCommitment(_)
+            | JobRequest::GenerateWinningPost(_)
+            | JobRequest::GenerateWindowPost(_)
+            | JobRequest::TestPolling(_) => Ok(()),
+        }
+    }
+}
+
+impl Estimate for SealPreCommitPhase1Data {
+    fn requirements(&self) -> Requirements {
+        let size = sector_size(self.registered_proof);
+        Requirements::memory(2 * size)
+            .with_disk(&self.cache_path, layers(self.registered_proof) * size)
+            .with_disk(&self.out_path, size)
+    }
+}
+
+impl Estimate for SealPreCommitPhase2Data {
+    fn requirements(&self) -> Requirements {
+        let size = sector_size(self.phase1_output.registered_proof);
+        Requirements::memory(size).with_disk(&self.cache_path, size + size / 4)
+    }
+}
+
+impl Estimate for SealCommitPhase1Data {
+    fn requirements(&self) -> Requirements {
+        Requirements::memory(sector_size(self.pre_commit.registered_proof) / 32)
+    }
+}
+
+impl Estimate for SealCommitPhase2Data {
+    fn requirements(&self) -> Requirements {
+        Requirements::memory(4 * sector_size(self.phase1_output.registered_proof))
+    }
+}
+
+impl Estimate for GetUnsealedRangeData {
+    fn requirements(&self) -> Requirements {
+        let size = sector_size(self.registered_proof);
+        Requirements::memory(2 * size)
+            .with_disk(&self.cache_path, layers(self.registered_proof) * size)
+            .with_disk(&self.output_path, u64::from(self.num_bytes))
+    }
+}
+
+impl Estimate for AddPieceData {
+    fn requirements(&self) -> Requirements {
+        Requirements::default().with_disk(&self.target, u64::from(self.piece_size))
+    }
+}
+
+impl Estimate for WriteAndPreprocessData {
+    fn requirements(&self) -> Requirements {
+        Requirements::default().with_disk(&self.target, u64::from(self.piece_size))
+    }
+}
+
+impl Estimate for StageSectorData {
+    fn requirements(&self) -> Requirements {
+        Requirements::default().with_disk(&self.target, sector_size(self.registered_proof) / 128 * 127)
+    }
+}
+
+/// commP only reads its source
+impl Estimate for GeneratePieceCommitmentData {
+    fn requirements(&self) -> Requirements {
+        Requirements::default()
+    }
+}
+
+/// winning and window PoSt, which read a few challenged nodes per sector
+impl Estimate for GenerateWinningPostData {
+    fn requirements(&self) -> Requirements {
+        Requirements::default()
+    }
+}
+
+/// layers of the stacked DRG, 11 for 32GiB and larger sectors and 2 for the test sizes
+fn layers(registered_proof: RegisteredSealProof) -> u64 {
+    if sector_size(registered_proof) >= 32 << 30 {
+        11
+    } else {
+        2
+    }
+}
+
+fn sector_size(registered_proof: RegisteredSealProof) -> u64 {
+    u64::from(registered_proof.sector_size())
+}
+
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// cut off what a job appended to `path`, nothing to do if the previous length is unknown
+fn truncate(path: &Path, len: Option<u64>) -> io::Result<()> {
+    let len = match len {
+        Some(len) => len,
+        None => return Ok(()),
+    };
+
+    match OpenOptions::new().write(true).open(path) {
+        Ok(file) => file.set_len(len),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// remove the files in `dir` whose name starts with one of `prefixes`
+fn remove_cache_files(dir: &Path, prefixes: &[&str]) -> io::Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let matched = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .map(|name| prefixes.iter().any(|x| name.starts_with(x)))
+            .unwrap_or(false);
+
+        if matched {
+            remove_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct WorkerProgress {
+    pub phase: Option<String>,
+    pub percent: Option<f64>,
+    /// layers to label, for PC1
+    layers: Option<u64>,
+}
+
+impl WorkerProgress {
+    /// update from a log line of the worker
+    fn update(&mut self, kind: JobKind, line: &str) {
+        for (_, marker, phase) in PHASE_MARKERS.iter().filter(|x| x.0 == kind) {
+            if line.contains(marker) {
+                self.phase = Some(phase.to_string());
+            }
+        }
+
+        if let (Some(layers), Some(i)) = (self.layers, line.find(LAYER_MARKER)) {
+            let layer: Option<u64> = line[i + LAYER_MARKER.len()..]
+                .split(|c: char| !c.is_ascii_digit())
+                .next()
+                .and_then(|x| x.parse().ok());
+
+            if let Some(layer) = layer {
+                self.phase = Some(format!("layer {}/{}", layer, layers));
+                self.percent = Some(layer.saturating_sub(1) as f64 * 100.0 / layers as f64);
+            }
+        }
+    }
+}
+
+/// Result of a job written to the result directory instead of being kept in memory
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoredResult {
+    /// token of the job
+    pub id: JobToken,
+    pub size: u64,
+    /// hex encoded
+    pub sha256: String,
+}
+
+impl StoredResult {
+    fn path(id: JobToken) -> PathBuf {
+        CONFIG.state_dir.join(RESULT_DIR).join(format!("{}.json", id))
+    }
+
+    pub fn open(&self) -> io::Result<File> {
+        File::open(Self::path(self.id))
+    }
+
+    /// drop the result file of a job, if it has one
+    pub fn remove(id: JobToken) {
+        if let Err(e) = remove_file(&Self::path(id)) {
+            warn!("remove result of job {} failed: {:?}", id, e);
+        }
+    }
+}
+
+/// Output of a finished worker, the same json either way
+#[derive(Clone, Debug)]
+pub enum JobOutput {
+    Value(Value),
+    Stored(StoredResult),
+}
+
+impl JobOutput {
+    /// journal form, `result` is null for stored results
+    pub fn into_parts(self) -> (Value, Option<StoredResult>) {
+        match self {
+            JobOutput::Value(result) => (result, None),
+            JobOutput::Stored(stored) => (Value::Null, Some(stored)),
+        }
+    }
+
+    pub fn from_parts(result: Value, stored: Option<StoredResult>) -> Self {
+        match stored {
+            Some(stored) => JobOutput::Stored(stored),
+            None => JobOutput::Value(result),
+        }
+    }
+
+    /// the result json, read back from the result directory for stored results
+    pub fn value(&self) -> io::Result<Value> {
+        match self {
+            JobOutput::Value(result) => Ok(result.clone()),
+            JobOutput::Stored(stored) => Ok(serde_json::from_reader(BufReader::new(stored.open()?))?),
+        }
+    }
+
+    /// the error a proof returned, stored results are over the inline limit and never an error
+    pub fn error(&self) -> Option<ApiError> {
+        match self {
+            JobOutput::Value(result) => result.get("Err").and_then(|x| serde_json::from_value(x.clone()).ok()),
+            JobOutput::Stored(_) => None,
+        }
+    }
+
+    pub fn state(&self) -> PollingState {
+        match self {
+            JobOutput::Value(result) => PollingState::Done(result.clone()),
+            JobOutput::Stored(stored) => PollingState::Stored(stored.clone()),
+        }
+    }
+}
+
+/// Shared between a job thread and `ServState`
+#[derive(Default)]
+pub struct JobControl {
+    cancelled: AtomicBool,
+    child: Mutex<Option<Child>>,
+    progress: Mutex<WorkerProgress>,
+}
+
+impl JobControl {
+    pub fn progress(&self) -> WorkerProgress {
+        self.progress.lock().clone()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// kill the worker process, the job thread reaps it and returns
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+
+        if let Some(child) = self.child.lock().as_mut() {
+            if let Err(e) = child.kill() {
+                warn!("kill worker {} failed: {:?}", child.id(), e);
+            }
+        }
+    }
+
+    fn wait(&self) -> io::Result<ExitStatus> {
+        loop {
+            if let Some(child) = self.child.lock().as_mut() {
+                // the child may have been spawned after `cancel` was called
+                if self.is_cancelled() {
+                    let _ = child.kill();
+                }
+
+                if let Some(status) = child.try_wait()? {
+                    return Ok(status);
+                }
+            }
+
+            thread::sleep(WORKER_POLL_INTERVAL);
+        }
+    }
+}
+
+/// run `request` in a worker subprocess, blocks until the worker exits
+pub fn run_in_worker(
+    token: JobToken,
+    request: &JobRequest,
+    control: &Arc<JobControl>,
+    cores: Option<&[usize]>,
+) -> Result<JobOutput, PollingError> {
+    let work_dir = CONFIG.state_dir.join("work");
+    let request_path = work_dir.join(format!("{}.request", token));
+    let result_path = work_dir.join(format!("{}.result", token));
+
+    let r: Result<JobOutput, PollingError> = spawn_worker(&request_path, &result_path, request, control, cores)
+        .and_then(|status| {
+            if status.success() {
+                load_result(token, &result_path).map(Ok)
+            } else if control.is_cancelled() {
+                Ok(Err(PollingError::Cancelled))
+            } else {
+                Ok(Err(PollingError::WorkerFailed(format!("worker {}", status))))
+            }
+        })
+        .unwrap_or_else(|e| Err(PollingError::WorkerFailed(format!("{:?}", e))));
+
+    for path in [&request_path, &result_path].iter() {
+        if let Err(e) = fs::remove_file(path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("remove {:?} failed: {:?}", path, e);
+            }
+        }
+    }
+
+    r
+}
+
+/// small results are parsed, larger ones are moved to the result directory untouched
+fn load_result(token: JobToken, result_path: &Path) -> io::Result<JobOutput> {
+    let size = fs::metadata(result_path)?.len();
+    if size <= CONFIG.result_inline_limit {
+        let result: Value = serde_json::from_reader(BufReader::new(File::open(result_path)?))?;
+        return Ok(JobOutput::Value(result));
+    }
+
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(result_path)?, &mut hasher)?;
+
+    let path = StoredResult::path(token);
+    fs::create_dir_all(path.parent().unwrap())?;
+    fs::rename(result_path, &path)?;
+
+    Ok(JobOutput::Stored(StoredResult {
+        id: token,
+        size,
+        sha256: hex::encode(hasher.finalize()),
+    }))
+}
+
+fn spawn_worker(
+    request_path: &Path,
+    result_path: &Path,
+    request: &JobRequest,
+    control: &Arc<JobControl>,
+    cores: Option<&[usize]>,
+) -> io::Result<ExitStatus> {
+    fs::create_dir_all(request_path.parent().unwrap())?;
+    let mut writer = BufWriter::new(File::create(request_path)?);
+    serde_json::to_writer(&mut writer, request)?;
+    writer.flush()?;
+
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .arg("worker")
+        .arg(request_path)
+        .arg(result_path)
+        .env("RUST_LOG", WORKER_LOG)
+        .stderr(Stdio::piped());
+
+    // the worker is killed when the job thread spawning it goes away, so it can not outlive a crashed server
+    let parent = unsafe { libc::getpid() };
+    let cores = cores.map(|x| x.to_vec());
+    unsafe {
+        command.pre_exec(move || {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // the server may have exited before the signal was set up
+            if libc::getppid() != parent {
+                return Err(io::Error::from_raw_os_error(libc::ESRCH));
+            }
+
+            // threads of the worker inherit the affinity
+            match &cores {
+                Some(cores) => set_affinity(cores),
+                None => Ok(()),
+            }
+        });
+    }
+    let mut child = command.spawn()?;
+
+    // pass the worker log through and watch it for progress
+    let stderr = child.stderr.take();
+    let kind = request.meta().kind;
+    control.progress.lock().layers = request.layers();
+    let progress_control = control.clone();
+    let log_handle = thread::spawn(move || {
+        let reader = match stderr {
+            Some(stderr) => BufReader::new(stderr),
+            None => return,
+        };
+
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+
+            // the line already carries the level and target of the worker
+            info!("{}", line);
+            progress_control.progress.lock().update(kind, &line);
+        }
+    });
+    *control.child.lock() = Some(child);
+
+    let status = control.wait();
+    if log_handle.join().is_err() {
+        warn!("worker log thread panicked");
+    }
+
+    status
+}
+
+/// entry of the `worker` subcommand
+pub fn worker_main(request_path: &Path, result_path: &Path) -> io::Result<()> {
+    let request: JobRequest = serde_json::from_reader(BufReader::new(File::open(request_path)?))?;
+    let result = request.execute();
+
+    let mut writer = BufWriter::new(File::create(result_path)?);
+    serde_json::to_writer(&mut writer, &result)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(kind: JobKind, layers: Option<u64>, lines: &[&str]) -> WorkerProgress {
+        let mut progress = WorkerProgress {
+            layers,
+            ..Default::default()
+        };
+        for line in lines {
+            progress.update(kind, line);
+        }
+        progress
+    }
+
+    #[test]
+    fn pc1_layers() {
+        let progress = feed(
+            JobKind::SealPreCommitPhase1,
+            Some(11),
+            &[
+                "2020-09-01T08:12:43.512 INFO storage_proofs_porep::stacked::vanilla::proof > generating layer: 1",
+                "2020-09-01T09:01:02.077 INFO storage_proofs_porep::stacked::vanilla::proof > storing labels on disk",
+                "2020-09-01T09:01:05.310 INFO storage_proofs_porep::stacked::vanilla::proof > generating layer: 3",
+            ],
+        );
+
+        assert_eq!(progress.phase.as_deref(), Some("layer 3/11"));
+        assert_eq!(progress.percent, Some(200.0 / 11.0));
+    }
+
+    #[test]
+    fn pc2_trees() {
+        let progress = feed(
+            JobKind::SealPreCommitPhase2,
+            None,
+            &[
+                "2020-09-01T12:00:00.001 INFO storage_proofs_porep::stacked::vanilla::proof > building tree_c",
+                "2020-09-01T12:20:00.001 INFO storage_proofs_porep::stacked::vanilla::proof > building tree_r_last",
+            ],
+        );
+
+        assert_eq!(progress.phase.as_deref(), Some("building tree r last"));
+        assert_eq!(progress.percent, None);
+    }
+
+    #[test]
+    fn c2_phases() {
+        let lines = [
+            "2020-09-01T13:00:00.000 INFO filecoin_proofs::api::seal > seal_commit_phase2:start",
+            "2020-09-01T13:00:09.000 INFO filecoin_proofs::api::seal > snark_proof:start",
+            "2020-09-01T13:10:00.000 INFO bellperson::groth16::prover > synthesis time: 591.2s",
+            "2020-09-01T13:40:00.000 INFO filecoin_proofs::api::seal > snark_proof:finish",
+        ];
+        let phases = ["loading parameters", "circuit synthesis", "proving", "finishing"];
+
+        for n in 0..lines.len() {
+            let progress = feed(JobKind::SealCommitPhase2, None, &lines[..=n]);
+            assert_eq!(progress.phase.as_deref(), Some(phases[n]));
+        }
+    }
+
+    #[test]
+    fn markers_of_other_kinds_are_ignored() {
+        let progress = feed(
+            JobKind::SealCommitPhase1,
+            None,
+            &["2020-09-01T13:00:09.000 INFO filecoin_proofs::api::seal > snark_proof:start"],
+        );
+
+        assert_eq!(progress.phase, None);
+    }
+
+    #[test]
+    fn worker_log_enables_marker_targets() {
+        for target in &["filecoin_proofs", "storage_proofs_porep", "bellperson"] {
+            assert!(WORKER_LOG.split(',').any(|x| x == format!("{}=info", target)));
+        }
+    }
+}
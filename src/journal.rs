@@ -0,0 +1,122 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::jobs::StoredResult;
+use crate::polling::{unix_now, JobMeta, JobToken, PollingError};
+use crate::webhook::CallbackStatus;
+
+const JOURNAL_FILE: &str = "jobs.journal";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum JournalEntry {
+    Submitted {
+        token: JobToken,
+        meta: JobMeta,
+    },
+    Started {
+        token: JobToken,
+        time: u64,
+    },
+    /// `result` is null when the result was stored as a file
+    Finished {
+        token: JobToken,
+        time: u64,
+        result: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stored: Option<StoredResult>,
+    },
+    Failed {
+        token: JobToken,
+        time: u64,
+        error: PollingError,
+    },
+    Interrupted {
+        token: JobToken,
+        time: u64,
+    },
+    /// failed with a transient error and queued again
+    Retrying {
+        token: JobToken,
+        attempt: u32,
+    },
+    Removed {
+        token: JobToken,
+    },
+    /// outcome of posting the final state to the callback url
+    Callback {
+        token: JobToken,
+        status: CallbackStatus,
+    },
+    /// result dropped by the retention policy, the token is kept as a tombstone until `tombstone_ttl` after `time`
+    Expired {
+        token: JobToken,
+        /// entries written before the time was recorded count from the replay
+        #[serde(default = "unix_now")]
+        time: u64,
+    },
+}
+
+/// Append-only job journal, one json encoded `JournalEntry` per line
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// open the journal in `dir` and return every entry recorded so far
+    pub fn open(dir: &Path) -> io::Result<(Self, Vec<JournalEntry>)> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(JOURNAL_FILE);
+
+        let mut entries = vec![];
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            for (n, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+
+                // a crash in the middle of a write leaves a truncated last line
+                match serde_json::from_str(&line) {
+                    Ok(entry) => entries.push(entry),
+                    Err(e) => warn!("skip broken journal line {}: {:?}", n + 1, e),
+                }
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok((Self { path, file }, entries))
+    }
+
+    pub fn append(&mut self, entry: &JournalEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        self.file.write_all(&line)?;
+        self.file.sync_data()
+    }
+
+    /// replace the journal content with `entries`
+    pub fn compact(&mut self, entries: &[JournalEntry]) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            for entry in entries {
+                serde_json::to_writer(&mut tmp, entry)?;
+                tmp.write_all(b"\n")?;
+            }
+            tmp.sync_all()?;
+        }
+
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+
+        Ok(())
+    }
+}
@@ -1,6 +1,20 @@
+pub mod affinity;
+pub mod api_error;
+pub mod auth;
+pub mod config;
+pub mod history;
+pub mod jobs;
+pub mod journal;
+pub mod pipeline;
 pub mod polling;
 pub mod post;
 pub mod post_data;
+pub mod registry;
+pub mod resources;
 pub mod seal;
 pub mod seal_data;
+pub mod sectors;
+pub mod staged;
 pub mod types;
+pub mod validate;
+pub mod webhook;
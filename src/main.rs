@@ -1,23 +1,48 @@
 use std::env;
-use std::sync::{Arc, Mutex};
+use std::path::Path;
+use std::sync::Arc;
 
 // use actix_web::FromRequest;
 use actix_web::{error, middleware, web};
-use actix_web::{App, HttpRequest, HttpResponse, HttpServer};
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, ResponseError};
 use log::{error, warn};
+use parking_lot::Mutex;
 
 // use crate::seal_data::SealCommitPhase2Data;
-use polling::ServState;
+use api_error::{ApiError, ErrorCode};
+use polling::{spawn_scheduler, spawn_sweeper, ServState};
+use validate::ValidationErrors;
+use webhook::spawn_webhook;
 
+mod affinity;
+mod api_error;
+mod auth;
+mod config;
+mod history;
+mod jobs;
+mod journal;
+mod pipeline;
 mod polling;
+mod registry;
+mod resources;
+mod sectors;
+mod staged;
 pub mod post;
 pub mod post_data;
 pub mod seal;
 pub mod seal_data;
 mod system;
 mod types;
+mod validate;
+mod webhook;
+
+fn query_error_handler(err: error::QueryPayloadError, _req: &HttpRequest) -> error::Error {
+    error!("{:?}", err);
+
+    let response = ValidationErrors::query(&err).into();
+    error::InternalError::from_response(err, response).into()
+}
 
-#[allow(dead_code)]
 fn json_error_handler(err: error::JsonPayloadError, _req: &HttpRequest) -> error::Error {
     error!("{:?}", err);
 
@@ -26,7 +51,8 @@ fn json_error_handler(err: error::JsonPayloadError, _req: &HttpRequest) -> error
         error::JsonPayloadError::ContentType => HttpResponse::UnsupportedMediaType()
             .content_type("text/plain")
             .body(detail),
-        _ => HttpResponse::BadRequest().content_type("text/plain").body(detail),
+        error::JsonPayloadError::Deserialize(e) => ValidationErrors::body(e).into(),
+        _ => ApiError::new(ErrorCode::InvalidInput, detail).error_response(),
     };
     error::InternalError::from_response(err, response).into()
 }
@@ -34,7 +60,6 @@ fn json_error_handler(err: error::JsonPayloadError, _req: &HttpRequest) -> error
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     let args: Vec<String> = env::args().collect();
-    let bind_address = &args[1];
 
     if std::env::var("RUST_LOG").is_err() {
         if cfg!(debug_assertions) {
@@ -45,21 +70,49 @@ async fn main() -> std::io::Result<()> {
     }
 
     fil_logger::init();
+
+    // heavy jobs run in a subprocess so they can be killed safely
+    if args.len() == 4 && args[1] == "worker" {
+        return jobs::worker_main(Path::new(&args[2]), Path::new(&args[3]));
+    }
+
+    let bind_address = &args[1];
     std::fs::create_dir_all("/tmp/upload/")?;
-    let state = Arc::new(Mutex::new(ServState::new()));
+    let state = Arc::new(Mutex::new(ServState::new()?));
+    let registry = state.lock().registry();
+    let sectors = state.lock().sectors();
+    spawn_scheduler(state.clone());
+    spawn_sweeper(state.clone());
+    spawn_webhook(state.clone());
 
     warn!("Listening: {}", bind_address);
 
     HttpServer::new(move || {
         let state = state.clone();
+        let registry = registry.clone();
+        let sectors = sectors.clone();
 
         App::new()
             .app_data(web::Data::new(state))
+            .app_data(web::Data::new(registry))
+            .app_data(web::Data::new(sectors))
+            .app_data(web::JsonConfig::default().error_handler(json_error_handler))
+            .app_data(web::QueryConfig::default().error_handler(query_error_handler))
             .wrap(middleware::Logger::default())
             .service(web::resource("/test").route(web::get().to(system::test)))
             .service(web::resource("/sys/test_polling").route(web::post().to(system::test_polling)))
             .service(web::resource("/sys/query_state").route(web::post().to(system::query_state)))
+            .service(web::resource("/sys/wait_job").route(web::get().to(system::wait_job)))
+            .service(web::resource("/sys/watch_job").route(web::get().to(system::watch_job)))
+            .service(web::resource("/sys/download_result").route(web::get().to(system::download_result)))
+            .service(web::resource("/sys/ack_job").route(web::post().to(system::ack_job)))
             .service(web::resource("/sys/remove_job").route(web::post().to(system::remove_job)))
+            .service(web::resource("/sys/retry_job").route(web::post().to(system::retry_job)))
+            .service(web::resource("/sys/list_jobs").route(web::post().to(system::list_jobs)))
+            .service(web::resource("/sys/job_detail").route(web::post().to(system::job_detail)))
+            .service(web::resource("/sys/job_stats").route(web::get().to(system::job_stats)))
+            .service(web::resource("/sys/list_sectors").route(web::get().to(system::list_sectors)))
+            .service(web::resource("/sys/sector_info").route(web::post().to(system::sector_info)))
             .service(web::resource("/sys/upload_file").route(web::post().to(system::upload_file)))
             .service(web::resource("/sys/upload_test").route(web::get().to(system::upload_test)))
             .service(
@@ -67,12 +120,28 @@ async fn main() -> std::io::Result<()> {
                     .route(web::post().to(post::generate_winning_post_sector_challenge)),
             )
             .service(web::resource("/post/generate_winning_post").route(web::post().to(post::generate_winning_post)))
+            .service(
+                web::resource("/post/generate_winning_post_async")
+                    .route(web::post().to(post::generate_winning_post_async)),
+            )
             .service(web::resource("/post/verify_winning_post").route(web::post().to(post::verify_winning_post)))
             .service(web::resource("/post/generate_window_post").route(web::post().to(post::generate_window_post)))
+            .service(
+                web::resource("/post/generate_window_post_async")
+                    .route(web::post().to(post::generate_window_post_async)),
+            )
             .service(web::resource("/post/verify_window_post").route(web::post().to(post::verify_window_post)))
             .service(web::resource("/seal/clear_cache").route(web::post().to(seal::clear_cache)))
             .service(web::resource("/seal/seal_pre_commit_phase1").route(web::post().to(seal::seal_pre_commit_phase1)))
+            .service(
+                web::resource("/seal/seal_pre_commit_phase1_async")
+                    .route(web::post().to(seal::seal_pre_commit_phase1_async)),
+            )
             .service(web::resource("/seal/seal_pre_commit_phase2").route(web::post().to(seal::seal_pre_commit_phase2)))
+            .service(
+                web::resource("/seal/seal_pre_commit_phase2_async")
+                    .route(web::post().to(seal::seal_pre_commit_phase2_async)),
+            )
             .service(web::resource("/seal/compute_comm_d").route(web::post().to(seal::compute_comm_d)))
             .service(web::resource("/seal/seal_commit_phase1").route(web::post().to(seal::seal_commit_phase1)))
             .service(
@@ -87,11 +156,30 @@ async fn main() -> std::io::Result<()> {
             .service(web::resource("/seal/verify_seal").route(web::post().to(seal::verify_seal)))
             .service(web::resource("/seal/verify_batch_seal").route(web::post().to(seal::verify_batch_seal)))
             .service(web::resource("/seal/get_unsealed_range").route(web::post().to(seal::get_unsealed_range)))
+            .service(
+                web::resource("/seal/get_unsealed_range_async").route(web::post().to(seal::get_unsealed_range_async)),
+            )
             .service(
                 web::resource("/seal/generate_piece_commitment").route(web::post().to(seal::generate_piece_commitment)),
             )
+            .service(
+                web::resource("/seal/generate_piece_commitment_async")
+                    .route(web::post().to(seal::generate_piece_commitment_async)),
+            )
             .service(web::resource("/seal/add_piece").route(web::post().to(seal::add_piece)))
+            .service(web::resource("/seal/add_piece_async").route(web::post().to(seal::add_piece_async)))
+            .service(web::resource("/seal/add_piece_stream").route(web::post().to(seal::add_piece_stream)))
             .service(web::resource("/seal/write_and_preprocess").route(web::post().to(seal::write_and_preprocess)))
+            .service(
+                web::resource("/seal/write_and_preprocess_async")
+                    .route(web::post().to(seal::write_and_preprocess_async)),
+            )
+            .service(web::resource("/seal/stage_sector").route(web::post().to(seal::stage_sector)))
+            .service(web::resource("/seal/stage_sector_async").route(web::post().to(seal::stage_sector_async)))
+            .service(web::resource("/seal/pipeline").route(web::post().to(seal::pipeline)))
+            .service(web::resource("/seal/pipeline_seed").route(web::post().to(seal::pipeline_seed)))
+            .service(web::resource("/seal/pipeline_state").route(web::post().to(seal::pipeline_state)))
+            .service(web::resource("/seal/pipeline_remove").route(web::post().to(seal::pipeline_remove)))
     })
     .bind(bind_address)
     .expect("Bind failed")
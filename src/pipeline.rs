@@ -0,0 +1,309 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::PathBuf;
+
+use filecoin_proofs_api::{ProverId, RegisteredSealProof, SectorId, Ticket};
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::api_error::{ApiError, ErrorCode};
+use crate::config::CONFIG;
+use crate::jobs::JobRequest;
+use crate::polling::{unix_now, JobInfo, JobToken, PollingError};
+use crate::seal_data::*;
+use crate::types::WebPieceInfo;
+
+/// pipeline records below `state_dir`, one json file per pipeline
+const PIPELINE_DIR: &str = "pipelines";
+
+/// Everything needed to seal one sector, body of `/seal/pipeline`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PipelineData {
+    pub registered_proof: RegisteredSealProof,
+    pub prover_id: ProverId,
+    pub sector_id: SectorId,
+    pub ticket: Ticket,
+    /// seed of C1, leave it out to wait for `/seal/pipeline_seed` after PC2
+    #[serde(default)]
+    pub seed: Option<Ticket>,
+    /// written to a new `staged_path` in order with the padding PC1 needs, leave it empty for an already staged sector
+    #[serde(default)]
+    pub pieces: Vec<StagePiece>,
+    /// pieces of an already staged sector, ignored when `pieces` is set
+    #[serde(default)]
+    pub piece_infos: Vec<WebPieceInfo>,
+    pub staged_path: String,
+    pub cache_path: String,
+    pub sealed_path: String,
+}
+
+/// Body of `/seal/pipeline_seed`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PipelineSeed {
+    pub token: JobToken,
+    pub seed: Ticket,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StepKind {
+    /// writes all pieces into the staged file, see `staged::stage_sector`
+    StageSector,
+    PreCommit1,
+    PreCommit2,
+    /// holds the pipeline until the seed is known
+    WaitSeed,
+    Commit1,
+    Commit2,
+}
+
+impl StepKind {
+    /// the step is built from the output of the step before it
+    pub fn uses_previous(self) -> bool {
+        match self {
+            StepKind::PreCommit2 | StepKind::Commit1 | StepKind::Commit2 => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PipelineStep {
+    pub kind: StepKind,
+    /// job running the step, `None` until it is submitted and for `WaitSeed`
+    pub job: Option<JobToken>,
+    pub done: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum PipelineStatus {
+    Running,
+    Done { time: u64 },
+    Failed { time: u64, error: PollingError },
+}
+
+/// Persistent record of a pipeline, the outputs of its steps stay in the step jobs until it is removed
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Pipeline {
+    pub token: JobToken,
+    pub owner: Option<String>,
+    /// priority of the step jobs, they keep the default of their kind if not set
+    pub priority: Option<i32>,
+    pub callback_url: Option<String>,
+    pub submit_time: u64,
+    pub data: PipelineData,
+    pub steps: Vec<PipelineStep>,
+    /// index of the first step not done
+    pub current: usize,
+    /// pieces written by the `stage_sector` step, or the given ones
+    pub piece_infos: Vec<WebPieceInfo>,
+    pub status: PipelineStatus,
+}
+
+impl Pipeline {
+    pub fn new(token: JobToken, data: PipelineData) -> Self {
+        let mut kinds = if data.pieces.is_empty() {
+            vec![]
+        } else {
+            vec![StepKind::StageSector]
+        };
+        kinds.extend_from_slice(&[StepKind::PreCommit1, StepKind::PreCommit2]);
+        if data.seed.is_none() {
+            kinds.push(StepKind::WaitSeed);
+        }
+        kinds.extend_from_slice(&[StepKind::Commit1, StepKind::Commit2]);
+
+        let piece_infos = if data.pieces.is_empty() {
+            data.piece_infos.clone()
+        } else {
+            vec![]
+        };
+
+        Self {
+            token,
+            owner: None,
+            priority: None,
+            callback_url: None,
+            submit_time: unix_now(),
+            steps: kinds
+                .into_iter()
+                .map(|kind| PipelineStep {
+                    kind,
+                    job: None,
+                    done: false,
+                })
+                .collect(),
+            current: 0,
+            piece_infos,
+            status: PipelineStatus::Running,
+            data,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        match self.status {
+            PipelineStatus::Running => true,
+            _ => false,
+        }
+    }
+
+    pub fn finish_time(&self) -> Option<u64> {
+        match self.status {
+            PipelineStatus::Running => None,
+            PipelineStatus::Done { time } | PipelineStatus::Failed { time, .. } => Some(time),
+        }
+    }
+
+    /// job of the latest finished step before the current one, its output feeds the current step
+    pub fn previous_job(&self) -> Option<JobToken> {
+        self.steps[..self.current].iter().rev().find_map(|x| x.job)
+    }
+
+    /// request of the current step, `previous` is the output of `previous_job`
+    pub fn request(&self, previous: Option<Value>) -> Result<JobRequest, PollingError> {
+        let data = &self.data;
+        let request = match self.steps[self.current].kind {
+            StepKind::StageSector => JobRequest::StageSector(StageSectorData {
+                registered_proof: data.registered_proof,
+                target: data.staged_path.clone(),
+                pieces: data.pieces.clone(),
+            }),
+            StepKind::PreCommit1 => JobRequest::SealPreCommitPhase1(SealPreCommitPhase1Data {
+                registered_proof: data.registered_proof,
+                cache_path: data.cache_path.clone(),
+                in_path: data.staged_path.clone(),
+                out_path: data.sealed_path.clone(),
+                prover_id: data.prover_id,
+                sector_id: data.sector_id,
+                ticket: data.ticket,
+                piece_infos: self.piece_infos.clone(),
+            }),
+            StepKind::PreCommit2 => JobRequest::SealPreCommitPhase2(SealPreCommitPhase2Data {
+                phase1_output: parse(previous)?,
+                cache_path: data.cache_path.clone(),
+                out_path: data.sealed_path.clone(),
+            }),
+            StepKind::Commit1 => JobRequest::SealCommitPhase1(SealCommitPhase1Data {
+                cache_path: data.cache_path.clone(),
+                replica_path: data.sealed_path.clone(),
+                prover_id: data.prover_id,
+                sector_id: data.sector_id,
+                ticket: data.ticket,
+                seed: data.seed.ok_or(PollingError::NotFinished)?,
+                pre_commit: parse(previous)?,
+                piece_infos: self.piece_infos.clone(),
+            }),
+            StepKind::Commit2 => JobRequest::SealCommitPhase2(SealCommitPhase2Data {
+                phase1_output: parse(previous)?,
+                prover_id: data.prover_id,
+                sector_id: data.sector_id,
+            }),
+            StepKind::WaitSeed => unreachable!("wait_seed runs no job"),
+        };
+
+        Ok(request)
+    }
+
+    /// take in the output of the current step and move on
+    pub fn complete_step(&mut self, output: &Value) -> Result<(), PollingError> {
+        if self.steps[self.current].kind == StepKind::StageSector {
+            let output: StageSectorOutput = parse(Some(output.clone()))?;
+            self.piece_infos = output.piece_infos;
+        }
+
+        self.steps[self.current].done = true;
+        self.current += 1;
+        Ok(())
+    }
+
+    fn path(token: JobToken) -> PathBuf {
+        CONFIG.state_dir.join(PIPELINE_DIR).join(format!("{}.json", token))
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = Self::path(self.token);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // written aside and renamed, a crash leaves either the old or the new record
+        let tmp_path = path.with_extension("tmp");
+        serde_json::to_writer(BufWriter::new(File::create(&tmp_path)?), self)?;
+        fs::rename(tmp_path, path)
+    }
+
+    pub fn discard(token: JobToken) {
+        if let Err(e) = fs::remove_file(Self::path(token)) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("remove pipeline {} failed: {:?}", token, e);
+            }
+        }
+    }
+
+    /// every pipeline saved before the restart
+    pub fn load_all() -> io::Result<Vec<Self>> {
+        let dir = CONFIG.state_dir.join(PIPELINE_DIR);
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut pipelines = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|x| x.to_str()) != Some("json") {
+                continue;
+            }
+
+            match File::open(&path).and_then(|x| Ok(serde_json::from_reader(BufReader::new(x))?)) {
+                Ok(pipeline) => pipelines.push(pipeline),
+                Err(e) => warn!("skip broken pipeline {:?}: {:?}", path, e),
+            }
+        }
+
+        Ok(pipelines)
+    }
+}
+
+/// the `Ok` side of a step result, as the worker wrote it
+pub fn step_output(result: Value) -> Result<Value, PollingError> {
+    match serde_json::from_value::<Result<Value, ApiError>>(result) {
+        Ok(Ok(output)) => Ok(output),
+        Ok(Err(e)) => Err(PollingError::StepFailed(e)),
+        Err(e) => Err(unexpected(e)),
+    }
+}
+
+fn parse<T: DeserializeOwned>(value: Option<Value>) -> Result<T, PollingError> {
+    let value = value.ok_or(PollingError::NotExist)?;
+    serde_json::from_value(value).map_err(unexpected)
+}
+
+/// a step output that does not parse
+pub fn unexpected<E: fmt::Display>(e: E) -> PollingError {
+    PollingError::StepFailed(ApiError::new(
+        ErrorCode::Internal,
+        format!("unexpected step output: {}", e),
+    ))
+}
+
+/// Per-step state of a pipeline, answer of `/seal/pipeline_state`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PipelineInfo {
+    pub token: JobToken,
+    pub prover_id: ProverId,
+    pub sector_id: SectorId,
+    pub submit_time: u64,
+    pub status: PipelineStatus,
+    pub steps: Vec<StepInfo>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StepInfo {
+    pub kind: StepKind,
+    pub done: bool,
+    /// the job of the step, once submitted
+    pub job: Option<JobInfo>,
+}
@@ -1,91 +1,1904 @@
-use std::collections::HashMap;
-use std::os::unix::thread::JoinHandleExt;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::mpsc::{Receiver, TryRecvError};
-use std::thread::JoinHandle;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::num::ParseIntError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use libc::pthread_cancel;
+use filecoin_proofs_api::{ProverId, SectorId};
+use futures::channel::mpsc::unbounded;
+use log::{error, info, warn};
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 
 use lazy_static::lazy_static;
 
+use crate::affinity::CoreAllocator;
+use crate::api_error::ApiError;
+use crate::auth::ClientId;
+use crate::config::CONFIG;
+use crate::history::{History, HistoryRecord, Outcome};
+use crate::jobs::{run_in_worker, JobControl, JobOutput, JobRequest, StoredResult};
+use crate::journal::{Journal, JournalEntry};
+use crate::pipeline::{
+    step_output, unexpected, Pipeline, PipelineData, PipelineInfo, PipelineSeed, PipelineStatus, StepInfo, StepKind,
+};
+use crate::registry::{JobRegistry, Snapshot};
+use crate::resources::{self, Requirements};
+use crate::sectors::SectorStore;
+use crate::webhook::{CallbackStatus, Delivery, DeliveryReceiver, DeliverySender};
+
 lazy_static! {
-    static ref WORKER_TOKEN: AtomicU64 = AtomicU64::new(0);
     static ref WORKER_INIT: AtomicBool = AtomicBool::new(false);
 }
 
+/// Random job id, sent as 32 hex digits
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(into = "String", try_from = "String")]
+pub struct JobToken(u128);
+
+impl JobToken {
+    pub fn random() -> Self {
+        JobToken(rand::random())
+    }
+
+    /// tokens are random, so the low bits spread them evenly
+    pub fn shard(self, shards: usize) -> usize {
+        (self.0 % shards as u128) as usize
+    }
+}
+
+impl fmt::Display for JobToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+impl From<JobToken> for String {
+    fn from(token: JobToken) -> Self {
+        token.to_string()
+    }
+}
+
+impl TryFrom<String> for JobToken {
+    type Error = ParseIntError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        u128::from_str_radix(&s, 16).map(JobToken)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum PollingState {
-    Started(u64),
-    Pending,
+    Started(JobToken),
+    /// waiting for a free slot, `position` is 1 for the next job of its kind to start
+    Queued {
+        position: usize,
+        /// why the job could not start yet though a slot is free, e.g. not enough memory
+        waiting: Option<String>,
+    },
+    Running(JobProgress),
     Done(Value),
-    Removed,
+    /// the result is too large to inline, download it from `/sys/download_result`
+    Stored(StoredResult),
+    Removed {
+        /// the job was killed before it finished
+        was_running: bool,
+        /// result of removing the partial outputs of a killed job
+        cleanup: Result<(), String>,
+    },
+    /// the result was not acknowledged within the retention time and has been dropped
+    Expired,
     Error(PollingError),
 }
 
+impl PollingState {
+    /// `Error` as the `ApiError` the handlers answer with
+    pub fn into_result(self) -> Result<Self, ApiError> {
+        match self {
+            PollingState::Error(e) => Err(e.into()),
+            state => Ok(state),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum PollingError {
     NotExist,
     Disconnected,
+    /// the server restarted while the job was queued or running
+    Interrupted,
+    Cancelled,
+    /// only finished jobs can be acknowledged
+    NotFinished,
+    /// the job needs more memory or disk than the server has
+    InsufficientResources(String),
+    /// the worker process crashed or could not be started
+    WorkerFailed(String),
+    /// a pipeline step returned an error
+    StepFailed(ApiError),
+}
+
+impl PollingError {
+    /// failures of the worker rather than of the proof, worth running again
+    pub fn is_transient(&self) -> bool {
+        match self {
+            PollingError::WorkerFailed(_) | PollingError::Disconnected => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    SealPreCommitPhase1,
+    SealPreCommitPhase2,
+    SealCommitPhase1,
+    SealCommitPhase2,
+    GetUnsealedRange,
+    GeneratePieceCommitment,
+    AddPiece,
+    WriteAndPreprocess,
+    StageSector,
+    GenerateWinningPost,
+    GenerateWindowPost,
+    TestPolling,
 }
 
-type WorkerReceiver = Receiver<Value>;
+impl JobKind {
+    /// concurrency limit used when `WEBAPI_MAX_JOBS` has no entry for this kind
+    pub fn default_max_jobs(self) -> usize {
+        match self {
+            JobKind::SealPreCommitPhase1 => 2,
+            JobKind::SealPreCommitPhase2 => 1,
+            JobKind::SealCommitPhase1 => 2,
+            JobKind::SealCommitPhase2 => 1,
+            JobKind::GetUnsealedRange => 1,
+            JobKind::GeneratePieceCommitment => 4,
+            JobKind::AddPiece => 2,
+            JobKind::WriteAndPreprocess => 2,
+            JobKind::StageSector => 2,
+            JobKind::GenerateWinningPost => 2,
+            JobKind::GenerateWindowPost => 1,
+            JobKind::TestPolling => 8,
+        }
+    }
+
+    /// PoSt has deadlines on chain and is dispatched ahead of seal work
+    pub fn default_priority(self) -> i32 {
+        match self {
+            JobKind::GenerateWinningPost => 100,
+            JobKind::GenerateWindowPost => 90,
+            _ => 0,
+        }
+    }
+
+    /// a sector goes through each seal phase once, so these are deduplicated per sector by default
+    pub fn is_seal_phase(self) -> bool {
+        match self {
+            JobKind::SealPreCommitPhase1
+            | JobKind::SealPreCommitPhase2
+            | JobKind::SealCommitPhase1
+            | JobKind::SealCommitPhase2 => true,
+            _ => false,
+        }
+    }
+
+    /// PoSt jobs may use the slots reserved by `WEBAPI_RESERVED_POST_SLOTS`
+    pub fn is_post(self) -> bool {
+        match self {
+            JobKind::GenerateWinningPost | JobKind::GenerateWindowPost => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JobMeta {
+    pub kind: JobKind,
+    pub prover_id: Option<ProverId>,
+    pub sector_id: Option<SectorId>,
+    /// registered seal or PoSt proof
+    #[serde(default)]
+    pub proof: Option<String>,
+    pub submit_time: u64,
+    /// higher runs first
+    #[serde(default)]
+    pub priority: i32,
+    /// submissions with the same key share one job until its result is acknowledged
+    #[serde(default)]
+    pub job_key: Option<String>,
+    /// the final state is posted here when the job finishes
+    #[serde(default)]
+    pub callback_url: Option<String>,
+    /// name of the client that submitted the job
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// automatic retries so far
+    #[serde(default)]
+    pub attempt: u32,
+    /// the failed job this one re-runs
+    #[serde(default)]
+    pub retry_of: Option<JobToken>,
+    /// the pipeline this job is a step of
+    #[serde(default)]
+    pub pipeline: Option<JobToken>,
+}
+
+impl JobMeta {
+    pub fn new(kind: JobKind) -> Self {
+        Self {
+            kind,
+            prover_id: None,
+            sector_id: None,
+            proof: None,
+            submit_time: unix_now(),
+            priority: kind.default_priority(),
+            job_key: None,
+            callback_url: None,
+            owner: None,
+            attempt: 0,
+            retry_of: None,
+            pipeline: None,
+        }
+    }
+
+    pub fn with_prover(mut self, prover_id: ProverId) -> Self {
+        self.prover_id = Some(prover_id);
+        self
+    }
+
+    pub fn with_sector(mut self, prover_id: ProverId, sector_id: SectorId) -> Self {
+        self.prover_id = Some(prover_id);
+        self.sector_id = Some(sector_id);
+        self
+    }
+
+    pub fn with_proof(mut self, proof: Option<String>) -> Self {
+        self.proof = proof;
+        self
+    }
+
+    /// entry of the key index, job keys are scoped to the submitting client
+    fn index_key(&self) -> Option<String> {
+        let owner = self.owner.as_ref().map(|x| x.as_str()).unwrap_or("");
+        self.job_key.as_ref().map(|key| format!("{}/{}", owner, key))
+    }
+
+    /// key of seal phases on a known sector, used when the client did not supply one;
+    /// the hash of the request keeps a corrected re-submission from getting the old job
+    fn derived_key(&self, request: &JobRequest) -> Option<String> {
+        match (&self.prover_id, self.sector_id) {
+            (Some(prover_id), Some(sector_id)) if self.kind.is_seal_phase() => {
+                let body = serde_json::to_vec(request).expect("requests serialize");
+                let hash = hex::encode(&Sha256::digest(&body)[..8]);
+                Some(format!(
+                    "{:?}-{}-{}-{}",
+                    self.kind,
+                    hex::encode(prover_id),
+                    u64::from(sector_id),
+                    hash
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JobProgress {
+    /// current stage, as far as the proofs log it
+    pub phase: String,
+    /// exact where the proofs report it, otherwise estimated from the average run time
+    pub percent: Option<f64>,
+    /// seconds since the job started
+    pub elapsed: u64,
+    /// estimated seconds left
+    pub eta: Option<u64>,
+}
+
+/// Optional submission settings, passed in the query string of a job request
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct JobOptions {
+    /// overrides `JobKind::default_priority`
+    pub priority: Option<i32>,
+    /// idempotency key, derived from the operation, prover_id and sector_id if not given
+    pub job_key: Option<String>,
+    /// url to post `{"token": .., "state": ..}` to once the job is `Done` or `Error`
+    pub callback_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JobInfo {
+    pub token: JobToken,
+    pub kind: JobKind,
+    pub prover_id: Option<ProverId>,
+    pub sector_id: Option<SectorId>,
+    pub priority: i32,
+    pub job_key: Option<String>,
+    pub submit_time: u64,
+    pub start_time: Option<u64>,
+    pub end_time: Option<u64>,
+    /// seconds spent running, up to now for unfinished jobs
+    pub elapsed: u64,
+    pub status: JobStatus,
+    /// queue position of a queued job
+    pub position: Option<usize>,
+    /// why the queued job has not started though a slot is free
+    pub waiting: Option<String>,
+    /// cores the job is pinned to
+    pub cores: Option<Vec<usize>>,
+    pub attempt: u32,
+    pub retry_of: Option<JobToken>,
+    pub progress: Option<JobProgress>,
+    pub error: Option<PollingError>,
+    pub callback_url: Option<String>,
+    pub callback: Option<CallbackStatus>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct JobFilter {
+    pub kind: Option<JobKind>,
+    pub status: Option<JobStatus>,
+}
+
+/// Query string of `/sys/wait_job` and `/sys/watch_job`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WaitOptions {
+    pub token: JobToken,
+    /// seconds `/sys/wait_job` holds the request, capped by `WEBAPI_MAX_WAIT`
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
+
+/// Query string of `/sys/download_result`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DownloadOptions {
+    pub id: JobToken,
+}
+
+impl JobFilter {
+    pub fn matches(&self, info: &JobInfo) -> bool {
+        self.kind.map(|x| x == info.kind).unwrap_or(true) && self.status.map(|x| x == info.status).unwrap_or(true)
+    }
+}
+
+type WorkerReceiver = Receiver<Result<JobOutput, PollingError>>;
+
+enum JobState {
+    Queued,
+    Running(JoinHandle<()>, WorkerReceiver),
+    Finished { time: u64, output: JobOutput },
+    Failed { time: u64, error: PollingError },
+}
+
+struct Job {
+    meta: JobMeta,
+    /// `None` for jobs restored from the journal
+    request: Option<Arc<JobRequest>>,
+    control: Arc<JobControl>,
+    start_time: Option<u64>,
+    state: JobState,
+    /// delivery of the final state, `None` without a callback url
+    callback: Option<CallbackStatus>,
+    /// why a queued job was passed over by the scheduler
+    waiting: Option<String>,
+    /// cores the worker is pinned to
+    cores: Option<Vec<usize>>,
+    /// a retried job waits for its backoff before it starts again
+    not_before: Option<u64>,
+    /// length of the file the job appends to when it started, see `JobRequest::target_len`
+    target_len: Option<u64>,
+}
+
+impl Job {
+    /// move a running job to its terminal state once the worker is done,
+    /// returns true if the job just finished
+    fn poll(&mut self) -> bool {
+        let next = match &self.state {
+            JobState::Running(_, rx) => match rx.try_recv() {
+                Ok(Ok(output)) => JobState::Finished {
+                    time: unix_now(),
+                    output,
+                },
+                Ok(Err(error)) => JobState::Failed {
+                    time: unix_now(),
+                    error,
+                },
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => JobState::Failed {
+                    time: unix_now(),
+                    error: PollingError::Disconnected,
+                },
+            },
+            _ => return false,
+        };
+
+        self.state = next;
+        true
+    }
+
+    /// final state to post to the callback url
+    fn delivery(&self, token: JobToken) -> Option<Delivery> {
+        let state = match &self.state {
+            JobState::Finished { output, .. } => output.state(),
+            JobState::Failed { error, .. } => PollingState::Error(error.clone()),
+            _ => return None,
+        };
+
+        Some(Delivery {
+            token,
+            url: self.meta.callback_url.clone()?,
+            state,
+        })
+    }
+
+    /// history of a job that just ended
+    fn history_record(&self, token: JobToken) -> Option<HistoryRecord> {
+        let (end_time, outcome, error) = match &self.state {
+            // a proof that returned an error still ends the worker cleanly
+            JobState::Finished { time, output } => match output.error() {
+                Some(error) => (*time, Outcome::Failed, Some(error.to_string())),
+                None => (*time, Outcome::Done, None),
+            },
+            JobState::Failed { time, error } => (*time, Outcome::Failed, Some(format!("{:?}", error))),
+            _ => return None,
+        };
+
+        Some(HistoryRecord {
+            end_time,
+            outcome,
+            error,
+            ..self.history_base(token)
+        })
+    }
+
+    fn history_base(&self, token: JobToken) -> HistoryRecord {
+        HistoryRecord {
+            token,
+            kind: self.meta.kind,
+            proof: self.meta.proof.clone(),
+            prover_id: self.meta.prover_id,
+            sector_id: self.meta.sector_id,
+            owner: self.meta.owner.clone(),
+            start_time: self.start_time.unwrap_or(self.meta.submit_time),
+            end_time: 0,
+            outcome: Outcome::Done,
+            error: None,
+        }
+    }
+
+    /// seconds to wait before running a failed job again, `None` if it should stay failed
+    fn retry_backoff(&self) -> Option<u64> {
+        let transient = match &self.state {
+            JobState::Failed { error, .. } => error.is_transient(),
+            JobState::Finished { output, .. } => output.error().map(|e| e.is_transient()).unwrap_or(false),
+            _ => false,
+        };
+        if !transient {
+            return None;
+        }
+        if self.meta.attempt as usize >= CONFIG.retries(self.meta.kind) {
+            return None;
+        }
+        if !self.request.as_ref().map(|x| x.can_retry()).unwrap_or(false) {
+            return None;
+        }
+
+        // doubles with every attempt
+        Some(CONFIG.retry_backoff.as_secs() << self.meta.attempt.min(10))
+    }
+
+    /// the worker failed or the proof returned an error
+    fn has_failed(&self) -> bool {
+        match &self.state {
+            JobState::Failed { .. } => true,
+            JobState::Finished { output, .. } => output.error().is_some(),
+            _ => false,
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        match &self.state {
+            JobState::Running(..) => true,
+            _ => false,
+        }
+    }
+
+    fn finish_time(&self) -> Option<u64> {
+        match &self.state {
+            JobState::Queued | JobState::Running(..) => None,
+            JobState::Finished { time, .. } | JobState::Failed { time, .. } => Some(*time),
+        }
+    }
+
+    /// the worker result of an ended job, a stored result is not read back
+    fn result(&self) -> Option<Value> {
+        match &self.state {
+            JobState::Queued | JobState::Running(..) => None,
+            JobState::Finished {
+                output: JobOutput::Value(value),
+                ..
+            } => Some(value.clone()),
+            JobState::Finished { .. } => Some(json!({ "Ok": null })),
+            JobState::Failed { error, .. } => Some(json!({ "Err": ApiError::from(error.clone()) })),
+        }
+    }
+
+    fn info(&self, token: JobToken, position: Option<usize>, progress: Option<JobProgress>) -> JobInfo {
+        let end_time = self.finish_time();
+        let elapsed = self
+            .start_time
+            .map(|x| end_time.unwrap_or_else(unix_now).saturating_sub(x))
+            .unwrap_or(0);
+        let (status, error) = match &self.state {
+            JobState::Queued => (JobStatus::Queued, None),
+            JobState::Running(..) => (JobStatus::Running, None),
+            JobState::Finished { .. } => (JobStatus::Done, None),
+            JobState::Failed { error, .. } => (JobStatus::Error, Some(error.clone())),
+        };
+
+        JobInfo {
+            token,
+            kind: self.meta.kind,
+            prover_id: self.meta.prover_id,
+            sector_id: self.meta.sector_id,
+            priority: self.meta.priority,
+            job_key: self.meta.job_key.clone(),
+            submit_time: self.meta.submit_time,
+            start_time: self.start_time,
+            end_time,
+            elapsed,
+            status,
+            position,
+            waiting: self.waiting.clone(),
+            cores: self.cores.clone(),
+            attempt: self.meta.attempt,
+            retry_of: self.meta.retry_of,
+            progress,
+            error,
+            callback_url: self.meta.callback_url.clone(),
+            callback: self.callback.clone(),
+        }
+    }
+}
 
 pub struct ServState {
-    workers: HashMap<u64, (JoinHandle<()>, WorkerReceiver)>,
+    workers: HashMap<JobToken, Job>,
+    /// queued tokens by priority, in submission order for the same priority
+    queue: VecDeque<JobToken>,
+    /// job key to token of jobs whose result has not been acknowledged
+    keys: HashMap<String, JobToken>,
+    /// number and total seconds of successful runs per kind
+    durations: HashMap<JobKind, (u64, u64)>,
+    journal: Arc<Mutex<Journal>>,
+    /// job threads report their token here when they exit
+    events: Sender<JobToken>,
+    events_rx: Option<Receiver<JobToken>>,
+    /// final states of finished jobs for the webhook thread
+    deliveries: DeliverySender,
+    deliveries_rx: Option<DeliveryReceiver>,
+    /// published job states, read by the status endpoints without this lock
+    registry: Arc<JobRegistry>,
+    /// core sets of running PC1 jobs
+    cores: CoreAllocator,
+    history: Arc<Mutex<History>>,
+    sectors: Arc<Mutex<SectorStore>>,
+    pipelines: HashMap<JobToken, Pipeline>,
+    /// pipelines with a step that just ended, advanced by the next `dispatch`
+    stale_pipelines: HashSet<JobToken>,
+    /// expiry time of the tombstones in the registry
+    tombstones: HashMap<JobToken, u64>,
+    /// pipeline step jobs reserved under the lock, `schedule` persists and queues them
+    unpersisted: Vec<(JobToken, Arc<JobRequest>, JobMeta)>,
 }
 
 impl ServState {
-    pub fn new() -> Self {
+    pub fn new() -> io::Result<Self> {
         // NOTE: ensure ServState is init only once
         assert_eq!(WORKER_INIT.swap(true, Ordering::SeqCst), false);
 
-        Self {
-            workers: HashMap::new(),
+        let (mut journal, entries) = Journal::open(&CONFIG.state_dir)?;
+
+        // replay journal, the terminal entry is `Finished`, `Failed` or `Interrupted`
+        let now = unix_now();
+        let mut jobs: HashMap<JobToken, ReplayedJob> = HashMap::new();
+        let mut expired = HashMap::new();
+        for entry in entries {
+            match entry {
+                JournalEntry::Submitted { token, meta } => {
+                    jobs.insert(
+                        token,
+                        ReplayedJob {
+                            meta,
+                            start_time: None,
+                            terminal: None,
+                            callback: None,
+                        },
+                    );
+                }
+                JournalEntry::Started { token, time } => {
+                    if let Some(job) = jobs.get_mut(&token) {
+                        job.start_time = Some(time);
+                    }
+                }
+                JournalEntry::Finished { token, .. }
+                | JournalEntry::Failed { token, .. }
+                | JournalEntry::Interrupted { token, .. } => {
+                    if let Some(job) = jobs.get_mut(&token) {
+                        job.terminal = Some(entry);
+                    }
+                }
+                JournalEntry::Callback { token, status } => {
+                    if let Some(job) = jobs.get_mut(&token) {
+                        job.callback = Some(status);
+                    }
+                }
+                JournalEntry::Retrying { token, attempt } => {
+                    if let Some(job) = jobs.get_mut(&token) {
+                        job.meta.attempt = attempt;
+                        job.start_time = None;
+                        job.terminal = None;
+                    }
+                }
+                JournalEntry::Removed { token } => {
+                    jobs.remove(&token);
+                }
+                JournalEntry::Expired { token, time } => {
+                    jobs.remove(&token);
+                    expired.insert(token, time);
+                }
+            }
+        }
+
+        // tokens are never reused, so old tombstones can go: their jobs just look like they never existed
+        let tombstone_deadline = now.saturating_sub(CONFIG.tombstone_ttl.as_secs());
+        expired.retain(|_, time| *time > tombstone_deadline);
+        let mut compacted: Vec<JournalEntry> = expired
+            .iter()
+            .map(|(&token, &time)| JournalEntry::Expired { token, time })
+            .collect();
+        let mut workers = HashMap::new();
+        let mut durations: HashMap<JobKind, (u64, u64)> = HashMap::new();
+        let mut pending = vec![];
+        for (token, job) in jobs {
+            let ReplayedJob {
+                meta,
+                start_time,
+                terminal,
+                callback,
+            } = job;
+            let terminal = terminal.unwrap_or_else(|| {
+                warn!("job {} {:?} interrupted by restart", token, meta.kind);
+                JournalEntry::Interrupted { token, time: now }
+            });
+            let state = match &terminal {
+                JournalEntry::Finished {
+                    time, result, stored, ..
+                } => {
+                    let output = JobOutput::from_parts(result.clone(), stored.clone());
+                    if let (Some(start_time), None) = (start_time, output.error()) {
+                        let stat = durations.entry(meta.kind).or_insert((0, 0));
+                        stat.0 += 1;
+                        stat.1 += time.saturating_sub(start_time);
+                    }
+
+                    JobState::Finished { time: *time, output }
+                }
+                JournalEntry::Failed { time, error, .. } => JobState::Failed {
+                    time: *time,
+                    error: error.clone(),
+                },
+                JournalEntry::Interrupted { time, .. } => JobState::Failed {
+                    time: *time,
+                    error: PollingError::Interrupted,
+                },
+                _ => unreachable!(),
+            };
+
+            compacted.push(JournalEntry::Submitted {
+                token,
+                meta: meta.clone(),
+            });
+            if let Some(time) = start_time {
+                compacted.push(JournalEntry::Started { token, time });
+            }
+            compacted.push(terminal);
+            if let Some(status) = &callback {
+                compacted.push(JournalEntry::Callback {
+                    token,
+                    status: status.clone(),
+                });
+            }
+
+            let job = Job {
+                waiting: None,
+                cores: None,
+                not_before: None,
+                target_len: None,
+                callback: meta
+                    .callback_url
+                    .as_ref()
+                    .map(|_| callback.unwrap_or(CallbackStatus::Pending)),
+                meta,
+                request: None,
+                control: Arc::new(JobControl::default()),
+                start_time,
+                state,
+            };
+
+            // retry callbacks cut short by the restart
+            if let Some(CallbackStatus::Pending) = job.callback {
+                pending.extend(job.delivery(token));
+            }
+            workers.insert(token, job);
+        }
+        journal.compact(&compacted)?;
+
+        info!("restored {} jobs", workers.len());
+
+        let keys = workers
+            .iter()
+            .filter(|(_, job)| !job.has_failed())
+            .filter_map(|(token, job)| job.meta.index_key().map(|key| (key, *token)))
+            .collect();
+
+        let (events, events_rx) = channel();
+        let (deliveries, deliveries_rx) = unbounded();
+        for delivery in pending {
+            deliveries.unbounded_send(delivery).expect("receiver is alive");
+        }
+
+        let registry = Arc::new(JobRegistry::new());
+        for token in expired.keys() {
+            registry.expire(*token);
+        }
+
+        let mut state = Self {
+            workers,
+            queue: VecDeque::new(),
+            keys,
+            durations,
+            journal: Arc::new(Mutex::new(journal)),
+            events,
+            events_rx: Some(events_rx),
+            deliveries,
+            deliveries_rx: Some(deliveries_rx),
+            registry,
+            cores: CoreAllocator::detect(),
+            history: Arc::new(Mutex::new(History::open(&CONFIG.state_dir)?)),
+            sectors: Arc::new(Mutex::new(SectorStore::open(&CONFIG.state_dir)?)),
+            pipelines: HashMap::new(),
+            stale_pipelines: HashSet::new(),
+            tombstones: expired,
+            unpersisted: vec![],
+        };
+
+        // steps that ended before the restart are taken in by the first dispatch
+        for pipeline in Pipeline::load_all()? {
+            if pipeline.is_running() {
+                state.stale_pipelines.insert(pipeline.token);
+            }
+            state.pipelines.insert(pipeline.token, pipeline);
         }
+        info!("restored {} pipelines", state.pipelines.len());
+
+        for token in state.workers.keys() {
+            state.publish(*token);
+        }
+        for token in state.pipelines.keys() {
+            state.publish_pipeline(*token);
+        }
+
+        Ok(state)
     }
 
-    pub fn enqueue(&mut self, handle: JoinHandle<()>, rx: WorkerReceiver) -> PollingState {
-        let token = WORKER_TOKEN.fetch_add(1, Ordering::SeqCst);
-        self.workers.insert(token, (handle, rx));
+    pub fn registry(&self) -> Arc<JobRegistry> {
+        self.registry.clone()
+    }
+
+    /// log of finished jobs, read without holding the state lock
+    pub fn history(&self) -> Arc<Mutex<History>> {
+        self.history.clone()
+    }
+
+    pub fn sectors(&self) -> Arc<Mutex<SectorStore>> {
+        self.sectors.clone()
+    }
+
+    /// receiver of the final states to post, taken once by the webhook thread
+    pub fn take_deliveries(&mut self) -> Option<DeliveryReceiver> {
+        self.deliveries_rx.take()
+    }
+
+    /// record the outcome of a callback, the job may already be gone
+    pub fn set_callback(&mut self, token: JobToken, status: CallbackStatus) {
+        if let Some(job) = self.workers.get_mut(&token) {
+            job.callback = Some(status.clone());
+            self.record(&JournalEntry::Callback { token, status });
+        }
+    }
+
+    /// queue a job for a client, the request and journal entry are written without holding the state lock
+    pub fn enqueue(state: &Mutex<Self>, request: JobRequest, options: JobOptions, client: &ClientId) -> PollingState {
+        let mut meta = request.meta();
+        if let Some(priority) = options.priority {
+            meta.priority = priority;
+        }
+        meta.owner = Some(client.name.clone());
+
+        // a re-sent submission gets the token of the job already running for it
+        meta.job_key = options.job_key.or_else(|| meta.derived_key(&request));
+        meta.callback_url = options.callback_url;
+
+        Self::submit(state, Arc::new(request), meta)
+    }
+
+    /// reserve, persist without the lock, then queue and dispatch the job
+    fn submit(state: &Mutex<Self>, request: Arc<JobRequest>, meta: JobMeta) -> PollingState {
+        let (token, journal) = {
+            let mut state = state.lock();
+            match state.reserve(&request, &meta) {
+                Ok(token) => (token, state.journal.clone()),
+                Err(response) => return response,
+            }
+        };
+        persist(token, &request, &meta, &journal);
+
+        state.lock().insert(token, request, meta);
+        Self::schedule(state);
 
         PollingState::Started(token)
     }
 
-    // TODO: remove if not query after long time
-    pub fn get(&mut self, token: u64) -> PollingState {
-        let state = self
+    /// dispatch, and persist and queue the pipeline steps it submitted until there are no more
+    pub fn schedule(state: &Mutex<Self>) {
+        loop {
+            let (steps, journal) = {
+                let mut state = state.lock();
+                state.dispatch();
+                if state.unpersisted.is_empty() {
+                    return;
+                }
+                (mem::take(&mut state.unpersisted), state.journal.clone())
+            };
+            for (token, request, meta) in steps.iter() {
+                persist(*token, request, meta, &journal);
+            }
+
+            let mut state = state.lock();
+            for (token, request, meta) in steps {
+                // the pipeline was removed while its step was written
+                let orphan = meta.pipeline.map(|x| !state.pipelines.contains_key(&x));
+                if orphan.unwrap_or(false) {
+                    release_key(&mut state.keys, &meta, token);
+                    state.registry.remove(token);
+                    state.record(&JournalEntry::Removed { token });
+                    JobRequest::discard(token);
+                    continue;
+                }
+                state.insert(token, request, meta);
+            }
+        }
+    }
+
+    /// token of a new job, or the answer when it is a duplicate or can never run here
+    fn reserve(&mut self, request: &JobRequest, meta: &JobMeta) -> Result<JobToken, PollingState> {
+        if let Some(token) = meta.index_key().and_then(|x| self.keys.get(&x)) {
+            info!("job key {:?} already submitted as {}", meta.job_key, token);
+            return Err(PollingState::Started(*token));
+        }
+
+        if let Some(reason) = resources::check_capacity(&request.requirements()) {
+            warn!("reject {:?} job: {}", meta.kind, reason);
+            return Err(PollingState::Error(PollingError::InsufficientResources(reason)));
+        }
+
+        let token = JobToken::random();
+        if let Some(key) = meta.index_key() {
+            self.keys.insert(key, token);
+        }
+        // a duplicate submitter may query the token before the job is queued
+        let position = self.queue_len(meta.kind) + 1;
+        self.registry.publish(
+            token,
+            &meta.owner,
+            Snapshot::Queued {
+                position,
+                waiting: None,
+            },
+        );
+
+        Ok(token)
+    }
+
+    /// add a reserved job to the queue once it is persisted
+    fn insert(&mut self, token: JobToken, request: Arc<JobRequest>, meta: JobMeta) {
+        self.workers.insert(
+            token,
+            Job {
+                callback: meta.callback_url.as_ref().map(|_| CallbackStatus::Pending),
+                waiting: None,
+                cores: None,
+                not_before: None,
+                target_len: None,
+                meta,
+                request: Some(request.clone()),
+                control: Arc::new(JobControl::default()),
+                start_time: None,
+                state: JobState::Queued,
+            },
+        );
+
+        self.queue_insert(token);
+    }
+
+    /// re-run a finished job under a new token, the old job is released
+    pub fn retry(state: &Mutex<Self>, token: JobToken, client: &ClientId) -> PollingState {
+        let (request, meta) = match state.lock().release_for_retry(token, client) {
+            Ok(job) => job,
+            Err(e) => return PollingState::Error(e),
+        };
+
+        Self::submit(state, request, meta)
+    }
+
+    /// request and meta of the new job, once the ended job is released
+    fn release_for_retry(
+        &mut self,
+        token: JobToken,
+        client: &ClientId,
+    ) -> Result<(Arc<JobRequest>, JobMeta), PollingError> {
+        self.check_access(token, client)?;
+        if self.is_pending(token) {
+            return Err(PollingError::NotFinished);
+        }
+
+        let job = self.workers.get(&token).ok_or(PollingError::NotExist)?;
+        let request = match job.request.clone() {
+            Some(request) => request,
+            None => match JobRequest::load(token) {
+                Ok(request) => Arc::new(request),
+                Err(e) => {
+                    warn!("load request of job {} failed: {:?}", token, e);
+                    return Err(PollingError::NotExist);
+                }
+            },
+        };
+
+        let mut meta = job.meta.clone();
+        meta.submit_time = unix_now();
+        meta.attempt = 0;
+        meta.retry_of = Some(token);
+
+        info!("retry job {}", token);
+        self.take(token);
+        self.registry.remove(token);
+        self.record(&JournalEntry::Removed { token });
+
+        Ok((request, meta))
+    }
+
+    /// behind every queued job with the same or a higher priority
+    fn queue_insert(&mut self, token: JobToken) {
+        let priority = match self.workers.get(&token) {
+            Some(job) => job.meta.priority,
+            None => return,
+        };
+
+        let index = self
+            .queue
+            .iter()
+            .position(|x| self.workers.get(x).map(|x| x.meta.priority < priority).unwrap_or(false))
+            .unwrap_or_else(|| self.queue.len());
+        self.queue.insert(index, token);
+    }
+
+    /// start queued jobs in queue order while their kind and the server are below the concurrency limits
+    fn dispatch(&mut self) {
+        self.poll_jobs();
+        for token in self.stale_pipelines.drain().collect::<Vec<_>>() {
+            self.advance_pipeline(token);
+        }
+
+        let mut running: HashMap<JobKind, usize> = HashMap::new();
+        for job in self.workers.values() {
+            if job.is_running() {
+                *running.entry(job.meta.kind).or_insert(0) += 1;
+            }
+        }
+
+        let mut reserved: Vec<Requirements> = self
             .workers
+            .values()
+            .filter(|x| x.is_running())
+            .filter_map(|x| x.request.as_ref().map(|x| x.requirements()))
+            .collect();
+
+        // kinds whose head-of-line job is held with its needs reserved
+        let mut holding: HashSet<JobKind> = HashSet::new();
+        let now = unix_now();
+        let mut total: usize = running.values().sum();
+        let mut i = 0;
+        while i < self.queue.len() {
+            let token = self.queue[i];
+            let kind = match self.workers.get(&token) {
+                Some(job) if job.not_before.map(|x| x > now).unwrap_or(false) => {
+                    i += 1;
+                    continue;
+                }
+                Some(job) => job.meta.kind,
+                None => {
+                    self.queue.remove(i);
+                    continue;
+                }
+            };
+
+            let count = running.entry(kind).or_insert(0);
+            if *count < CONFIG.max_jobs(kind) && total < CONFIG.max_total_jobs(kind) {
+                let need = self.workers[&token]
+                    .request
+                    .as_ref()
+                    .map(|x| x.requirements())
+                    .unwrap_or_default();
+
+                // leave it queued with its needs reserved, so jobs behind it only go ahead in what is left;
+                // only the first held job of a kind reserves, or a long queue would starve everything
+                if let Some(reason) = resources::check_available(&need, &reserved) {
+                    if let Some(job) = self.workers.get_mut(&token) {
+                        job.waiting = Some(reason);
+                    }
+                    if holding.insert(kind) {
+                        reserved.push(need);
+                    }
+                    i += 1;
+                    continue;
+                }
+
+                // concurrent PC1 jobs each get their own cores, a PC1 waits for a free set
+                let cores = if kind == JobKind::SealPreCommitPhase1 && CONFIG.pin_pc1 && self.cores.is_enabled() {
+                    match self.cores.allocate(token) {
+                        Some(cores) => Some(cores),
+                        None => {
+                            if let Some(job) = self.workers.get_mut(&token) {
+                                job.waiting = Some("no free core set".to_string());
+                            }
+                            i += 1;
+                            continue;
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                *count += 1;
+                total += 1;
+                reserved.push(need);
+                self.queue.remove(i);
+                self.start(token, cores);
+            } else {
+                i += 1;
+            }
+        }
+
+        self.publish_queue();
+    }
+
+    /// run the job in a worker, pinned to `cores` if set
+    fn start(&mut self, token: JobToken, cores: Option<Vec<usize>>) {
+        let job = match self.workers.get_mut(&token) {
+            Some(job) => job,
+            None => return,
+        };
+        let request = match &job.request {
+            Some(request) => request.clone(),
+            None => return,
+        };
+        job.cores = cores.clone();
+        job.target_len = request.target_len();
+
+        let control = job.control.clone();
+        let journal = self.journal.clone();
+        let events = self.events.clone();
+        let (tx, rx) = channel();
+        let handle: JoinHandle<()> = thread::spawn(move || {
+            run_job(token, &request, &control, cores.as_deref(), &journal, tx);
+
+            // let the scheduler start the next job
+            if let Err(e) = events.send(token) {
+                error!("{:?}", e);
+            }
+        });
+
+        let time = unix_now();
+        job.start_time = Some(time);
+        job.waiting = None;
+        job.not_before = None;
+        job.state = JobState::Running(handle, rx);
+
+        info!("start job {} {:?}", token, job.meta.kind);
+        self.record(&JournalEntry::Started { token, time });
+        self.publish(token);
+    }
+
+    /// release the result of a finished job
+    pub fn ack(&mut self, token: JobToken, client: &ClientId) -> Result<(), PollingError> {
+        self.check_access(token, client)?;
+        if self.is_pending(token) {
+            return Err(PollingError::NotFinished);
+        }
+
+        self.take(token).ok_or(PollingError::NotExist)?;
+        self.registry.remove(token);
+        self.record(&JournalEntry::Removed { token });
+
+        Ok(())
+    }
+
+    /// true while the job is queued or running
+    fn is_pending(&mut self, token: JobToken) -> bool {
+        self.poll_jobs();
+        self.workers
             .get(&token)
-            .map(|x| match x.1.try_recv() {
-                Ok(r) => PollingState::Done(r),
-                Err(TryRecvError::Empty) => PollingState::Pending,
-                Err(TryRecvError::Disconnected) => PollingState::Error(PollingError::Disconnected),
-            })
-            .unwrap_or(PollingState::Error(PollingError::NotExist));
+            .map(|x| x.finish_time().is_none())
+            .unwrap_or(false)
+    }
+
+    /// make the current state of the job visible to the registry and wake its watchers
+    fn publish(&self, token: JobToken) {
+        let job = match self.workers.get(&token) {
+            Some(job) => job,
+            None => return,
+        };
+
+        self.registry.publish(token, &job.meta.owner, self.snapshot(token, job));
+        if let Some(pipeline) = job.meta.pipeline {
+            self.publish_pipeline(pipeline);
+        }
+    }
+
+    fn snapshot(&self, token: JobToken, job: &Job) -> Snapshot {
+        match &job.state {
+            JobState::Queued => Snapshot::Queued {
+                position: self.queue_position(token).unwrap_or(0),
+                waiting: job.waiting.clone(),
+            },
+            JobState::Running(..) => Snapshot::Running {
+                control: job.control.clone(),
+                start_time: job.start_time.unwrap_or_else(unix_now),
+                average: self.average(job.meta.kind),
+            },
+            JobState::Finished { output, .. } => Snapshot::Done(output.clone()),
+            JobState::Failed { error, .. } => Snapshot::Error(error.clone()),
+        }
+    }
+
+    /// queue positions shift whenever a job is queued, started or removed
+    fn publish_queue(&self) {
+        let mut positions: HashMap<JobKind, usize> = HashMap::new();
+        for token in self.queue.iter() {
+            let job = match self.workers.get(token) {
+                Some(job) => job,
+                None => continue,
+            };
 
-        match &state {
-            PollingState::Done(_) => {
-                self.workers.remove(&token);
+            let position = positions.entry(job.meta.kind).or_insert(0);
+            *position += 1;
+            let snapshot = Snapshot::Queued {
+                position: *position,
+                waiting: job.waiting.clone(),
+            };
+            self.registry.publish(*token, &job.meta.owner, snapshot);
+            if let Some(pipeline) = job.meta.pipeline {
+                self.publish_pipeline(pipeline);
             }
-            _ => {}
+        }
+    }
+
+    /// jobs of the client, or of every client for admins
+    pub fn list(&mut self, filter: &JobFilter, client: &ClientId) -> Vec<JobInfo> {
+        self.poll_jobs();
+
+        let mut jobs: Vec<JobInfo> = self
+            .workers
+            .iter()
+            .filter(|(_, job)| client.can_access(&job.meta.owner))
+            .filter_map(|(token, _)| self.info(*token))
+            .filter(|x| filter.matches(x))
+            .collect();
+        jobs.sort_by_key(|x| x.submit_time);
+
+        jobs
+    }
+
+    pub fn detail(&mut self, token: JobToken, client: &ClientId) -> Option<JobInfo> {
+        self.check_access(token, client).ok()?;
+        self.poll_jobs();
+        self.info(token)
+    }
+
+    /// jobs of other clients look like they do not exist
+    fn check_access(&self, token: JobToken, client: &ClientId) -> Result<(), PollingError> {
+        match self.workers.get(&token) {
+            Some(job) if !client.can_access(&job.meta.owner) => Err(PollingError::NotExist),
+            _ => Ok(()),
+        }
+    }
+
+    fn info(&self, token: JobToken) -> Option<JobInfo> {
+        let job = self.workers.get(&token)?;
+        let progress = if job.is_running() {
+            Some(self.progress(job))
+        } else {
+            None
         };
 
-        state
+        Some(job.info(token, self.queue_position(token), progress))
     }
 
-    pub fn remove(&mut self, token: u64) -> PollingState {
-        if let Some((handle, _rx)) = self.workers.remove(&token) {
-            let pthread_t = handle.into_pthread_t();
+    fn poll_jobs(&mut self) {
+        let now = unix_now();
+        let mut finished = vec![];
+        let mut retries = vec![];
+        for (token, job) in self.workers.iter_mut() {
+            if !job.poll() {
+                continue;
+            }
+            finished.push(*token);
+            self.cores.release(*token);
+            if let Some(pipeline) = job.meta.pipeline {
+                self.stale_pipelines.insert(pipeline);
+            }
+            if let Some(record) = job.history_record(*token) {
+                if let Err(e) = self.history.lock().append(&record) {
+                    error!("write history of job {} failed: {:?}", token, e);
+                }
+            }
+
+            // partial outputs of a failed job are in the way of a retry, and useless without one
+            if let Some(request) = job.request.as_ref().filter(|_| job.has_failed()) {
+                if let Err(e) = request.cleanup(job.target_len) {
+                    warn!("cleanup of job {} failed: {:?}", token, e);
+                }
+            }
+
+            if let Some(backoff) = job.retry_backoff() {
+                job.meta.attempt += 1;
+                warn!("job {} failed, retry {} in {}s", token, job.meta.attempt, backoff);
+
+                job.state = JobState::Queued;
+                job.start_time = None;
+                job.not_before = Some(now + backoff);
+                job.waiting = Some(format!("retry {} after a transient failure", job.meta.attempt));
+                retries.push((*token, job.meta.attempt));
+                continue;
+            }
+
+            // a failed job holds no key, so re-sending the submission runs it again
+            if job.has_failed() {
+                release_key(&mut self.keys, &job.meta, *token);
+            }
+
+            if let (JobState::Finished { time, output }, Some(start_time)) = (&job.state, job.start_time) {
+                if output.error().is_none() {
+                    let stat = self.durations.entry(job.meta.kind).or_insert((0, 0));
+                    stat.0 += 1;
+                    stat.1 += time.saturating_sub(start_time);
+                }
+            }
 
-            unsafe {
-                pthread_cancel(pthread_t);
+            if let (Some(request), Some(result)) = (&job.request, job.result()) {
+                self.sectors
+                    .lock()
+                    .observe(request, &result, Some(*token), &job.meta.owner);
             }
 
-            return PollingState::Removed;
+            if let Some(delivery) = job.delivery(*token) {
+                if let Err(e) = self.deliveries.unbounded_send(delivery) {
+                    error!("queue callback of job {} failed: {:?}", token, e);
+                }
+            }
+        }
+
+        for (token, attempt) in retries {
+            self.record(&JournalEntry::Retrying { token, attempt });
+            self.queue_insert(token);
         }
 
-        PollingState::Error(PollingError::NotExist)
+        for token in finished {
+            self.publish(token);
+        }
+    }
+
+    fn progress(&self, job: &Job) -> JobProgress {
+        estimate_progress(&job.control, job.start_time, self.average(job.meta.kind))
+    }
+
+    /// seconds of the successful runs of `kind` so far
+    fn average(&self, kind: JobKind) -> Option<u64> {
+        self.durations
+            .get(&kind)
+            .filter(|x| x.0 > 0)
+            .map(|x| x.1 / x.0)
+            .filter(|x| *x > 0)
+    }
+
+    /// position among the queued jobs of the same kind, starting at 1
+    /// queued jobs of `kind`
+    fn queue_len(&self, kind: JobKind) -> usize {
+        self.queue
+            .iter()
+            .filter(|x| self.workers.get(x).map(|x| x.meta.kind) == Some(kind))
+            .count()
     }
+
+    fn queue_position(&self, token: JobToken) -> Option<usize> {
+        let kind = self.workers.get(&token)?.meta.kind;
+
+        let mut position = 0;
+        for t in self.queue.iter() {
+            if self.workers.get(t).map(|x| x.meta.kind) == Some(kind) {
+                position += 1;
+            }
+
+            if *t == token {
+                return Some(position);
+            }
+        }
+
+        None
+    }
+
+    /// drop finished results that have not been acknowledged within `CONFIG.result_ttl`
+    pub fn sweep(&mut self) {
+        let now = unix_now();
+        let deadline = now.saturating_sub(CONFIG.result_ttl.as_secs());
+
+        self.poll_jobs();
+
+        let mut expired = vec![];
+        for (token, job) in self.workers.iter() {
+            // outputs of pipeline steps live as long as their pipeline
+            let in_pipeline = job
+                .meta
+                .pipeline
+                .map(|x| self.pipelines.contains_key(&x))
+                .unwrap_or(false);
+            if !in_pipeline && job.finish_time().map(|x| x <= deadline).unwrap_or(false) {
+                expired.push(*token);
+            }
+        }
+
+        for token in expired {
+            info!("job {} expired", token);
+
+            self.take(token);
+            self.registry.expire(token);
+            self.tombstones.insert(token, now);
+            self.record(&JournalEntry::Expired { token, time: now });
+        }
+
+        let pipelines: Vec<JobToken> = self
+            .pipelines
+            .values()
+            .filter(|x| x.finish_time().map(|x| x <= deadline).unwrap_or(false))
+            .map(|x| x.token)
+            .collect();
+        for token in pipelines {
+            info!("pipeline {} expired", token);
+
+            for job in self.take_pipeline(token) {
+                self.take(job);
+                self.registry.remove(job);
+                self.record(&JournalEntry::Removed { token: job });
+            }
+            // replay restores the tombstone like one of a job, the pipeline file is gone
+            self.registry.expire(token);
+            self.tombstones.insert(token, now);
+            self.record(&JournalEntry::Expired { token, time: now });
+        }
+
+        // the journal drops them with the next compaction
+        let tombstone_deadline = now.saturating_sub(CONFIG.tombstone_ttl.as_secs());
+        let registry = &self.registry;
+        self.tombstones.retain(|token, time| {
+            let keep = *time > tombstone_deadline;
+            if !keep {
+                registry.remove(*token);
+            }
+            keep
+        });
+    }
+
+    /// take the job out of the table and kill its worker, call `RemovedJob::finish` to wait for it
+    pub fn remove(&mut self, token: JobToken, client: &ClientId) -> Result<RemovedJob, PollingError> {
+        self.check_access(token, client)?;
+        let mut job = self.take(token).ok_or(PollingError::NotExist)?;
+        self.registry.remove(token);
+        self.record(&JournalEntry::Removed { token });
+
+        job.poll();
+        if job.is_running() {
+            let record = HistoryRecord {
+                outcome: Outcome::Cancelled,
+                end_time: unix_now(),
+                ..job.history_base(token)
+            };
+            if let Err(e) = self.history.lock().append(&record) {
+                error!("write history of job {} failed: {:?}", token, e);
+            }
+        }
+
+        match job.state {
+            JobState::Running(handle, _rx) => {
+                info!("cancel job {}", token);
+                job.control.cancel();
+
+                Ok(RemovedJob {
+                    handle: Some(handle),
+                    request: job.request,
+                    target_len: job.target_len,
+                })
+            }
+            _ => Ok(RemovedJob {
+                handle: None,
+                request: None,
+                target_len: None,
+            }),
+        }
+    }
+
+    /// remove a job from the table, the queue, the key index and the core sets, the caller updates the registry
+    fn take(&mut self, token: JobToken) -> Option<Job> {
+        let job = self.workers.remove(&token)?;
+        self.queue.retain(|x| *x != token);
+        release_key(&mut self.keys, &job.meta, token);
+        self.cores.release(token);
+        self.publish_queue();
+        JobRequest::discard(token);
+        StoredResult::remove(token);
+
+        Some(job)
+    }
+
+    /// submit a pipeline, its steps run as jobs of the submitting client
+    pub fn enqueue_pipeline(
+        state: &Mutex<Self>,
+        data: PipelineData,
+        options: JobOptions,
+        client: &ClientId,
+    ) -> PollingState {
+        let token = JobToken::random();
+        let mut pipeline = Pipeline::new(token, data);
+        pipeline.owner = Some(client.name.clone());
+        pipeline.priority = options.priority;
+        pipeline.callback_url = options.callback_url;
+
+        info!("start pipeline {}", token);
+        {
+            let mut state = state.lock();
+            state.pipelines.insert(token, pipeline);
+            state.advance_pipeline(token);
+        }
+        Self::schedule(state);
+
+        PollingState::Started(token)
+    }
+
+    /// hand the C1 seed to a pipeline waiting for it
+    pub fn set_pipeline_seed(state: &Mutex<Self>, seed: PipelineSeed, client: &ClientId) -> PollingState {
+        {
+            let mut state = state.lock();
+            match state.pipelines.get_mut(&seed.token) {
+                Some(pipeline) if client.can_access(&pipeline.owner) => pipeline.data.seed = Some(seed.seed),
+                _ => return PollingState::Error(PollingError::NotExist),
+            }
+            state.advance_pipeline(seed.token);
+        }
+        Self::schedule(state);
+
+        state.lock().registry.get(seed.token, client)
+    }
+
+    pub fn pipeline_info(&self, token: JobToken, client: &ClientId) -> Option<PipelineInfo> {
+        let pipeline = self.pipelines.get(&token).filter(|x| client.can_access(&x.owner))?;
+
+        Some(PipelineInfo {
+            token,
+            prover_id: pipeline.data.prover_id,
+            sector_id: pipeline.data.sector_id,
+            submit_time: pipeline.submit_time,
+            status: pipeline.status.clone(),
+            steps: pipeline
+                .steps
+                .iter()
+                .map(|step| StepInfo {
+                    kind: step.kind,
+                    done: step.done,
+                    job: step.job.and_then(|x| self.info(x)),
+                })
+                .collect(),
+        })
+    }
+
+    /// drop the pipeline and its step jobs, a running step is killed like by `remove`
+    pub fn remove_pipeline(&mut self, token: JobToken, client: &ClientId) -> Result<RemovedJob, PollingError> {
+        match self.pipelines.get(&token) {
+            Some(pipeline) if client.can_access(&pipeline.owner) => {}
+            _ => return Err(PollingError::NotExist),
+        }
+
+        info!("remove pipeline {}", token);
+        self.registry.remove(token);
+
+        let mut removed = RemovedJob {
+            handle: None,
+            request: None,
+            target_len: None,
+        };
+        for job in self.take_pipeline(token) {
+            match self.remove(job, client) {
+                Ok(job) if job.handle.is_some() => removed = job,
+                _ => {}
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// forget the pipeline, returns the jobs of its steps
+    fn take_pipeline(&mut self, token: JobToken) -> Vec<JobToken> {
+        Pipeline::discard(token);
+        self.stale_pipelines.remove(&token);
+
+        self.pipelines
+            .remove(&token)
+            .map(|x| x.steps.iter().filter_map(|x| x.job).collect())
+            .unwrap_or_default()
+    }
+
+    /// take in the steps that ended and submit the next one, as far as the pipeline can go
+    fn advance_pipeline(&mut self, token: JobToken) {
+        // the output of the step just taken in, so it is not read twice
+        let mut previous: Option<Value> = None;
+
+        loop {
+            let pipeline = match self.pipelines.get_mut(&token) {
+                Some(pipeline) if pipeline.is_running() => pipeline,
+                _ => break,
+            };
+            if pipeline.current == pipeline.steps.len() {
+                info!("pipeline {} finished", token);
+                self.finish_pipeline(token, None);
+                break;
+            }
+
+            let step = pipeline.steps[pipeline.current].clone();
+            let r = match (step.kind, step.job) {
+                (StepKind::WaitSeed, _) if pipeline.data.seed.is_none() => break,
+                (StepKind::WaitSeed, _) => {
+                    pipeline.steps[pipeline.current].done = true;
+                    pipeline.current += 1;
+                    Ok(())
+                }
+                (_, None) => self.submit_step(token, previous.take()),
+                (_, Some(job)) if self.unpersisted.iter().any(|x| x.0 == job) => break,
+                (_, Some(job)) => match self.workers.get(&job).map(|x| &x.state) {
+                    Some(JobState::Queued) | Some(JobState::Running(..)) => break,
+                    Some(JobState::Finished { output, .. }) => output
+                        .value()
+                        .map_err(unexpected)
+                        .and_then(step_output)
+                        .and_then(|value| {
+                            pipeline.complete_step(&value)?;
+                            previous = Some(value);
+                            Ok(())
+                        }),
+                    Some(JobState::Failed { error, .. }) => Err(error.clone()),
+                    None => Err(PollingError::NotExist),
+                },
+            };
+
+            if let Err(error) = r {
+                warn!("pipeline {} failed at {:?}: {:?}", token, step.kind, error);
+                self.finish_pipeline(token, Some(error));
+                break;
+            }
+        }
+
+        if let Some(Err(e)) = self.pipelines.get(&token).map(|x| x.save()) {
+            error!("save pipeline {} failed: {:?}", token, e);
+        }
+        self.publish_pipeline(token);
+    }
+
+    /// queue the job of the current step
+    fn submit_step(&mut self, token: JobToken, previous: Option<Value>) -> Result<(), PollingError> {
+        let pipeline = &self.pipelines[&token];
+        let previous = match (previous, pipeline.previous_job()) {
+            (Some(previous), _) => Some(previous),
+            (None, Some(job)) if pipeline.steps[pipeline.current].kind.uses_previous() => {
+                let output = match self.workers.get(&job).map(|x| &x.state) {
+                    Some(JobState::Finished { output, .. }) => output.value(),
+                    _ => return Err(PollingError::NotExist),
+                };
+                let output = output.map_err(unexpected)?;
+                Some(step_output(output)?)
+            }
+            (None, _) => None,
+        };
+
+        let request = pipeline.request(previous)?;
+        let mut meta = request.meta();
+        meta.owner = pipeline.owner.clone();
+        if let Some(priority) = pipeline.priority {
+            meta.priority = priority;
+        }
+        meta.pipeline = Some(token);
+
+        // written and queued by `schedule` once the lock is released
+        let request = Arc::new(request);
+        match self.reserve(&request, &meta) {
+            Ok(job) => {
+                if let Some(pipeline) = self.pipelines.get_mut(&token) {
+                    let current = pipeline.current;
+                    pipeline.steps[current].job = Some(job);
+                }
+                self.unpersisted.push((job, request, meta));
+                Ok(())
+            }
+            Err(PollingState::Error(error)) => Err(error),
+            Err(state) => Err(unexpected(format!("{:?}", state))),
+        }
+    }
+
+    fn finish_pipeline(&mut self, token: JobToken, error: Option<PollingError>) {
+        let pipeline = match self.pipelines.get_mut(&token) {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+
+        let time = unix_now();
+        pipeline.status = match error {
+            Some(error) => PipelineStatus::Failed { time, error },
+            None => PipelineStatus::Done { time },
+        };
+
+        if let Some(url) = pipeline.callback_url.clone() {
+            let delivery = Delivery {
+                token,
+                url,
+                state: self.pipeline_state(token),
+            };
+            if let Err(e) = self.deliveries.unbounded_send(delivery) {
+                error!("queue callback of pipeline {} failed: {:?}", token, e);
+            }
+        }
+    }
+
+    /// the pipeline as one job: the state of its current step, the result of its last one
+    fn pipeline_snapshot(&self, pipeline: &Pipeline) -> Snapshot {
+        match &pipeline.status {
+            PipelineStatus::Done { .. } => {
+                let last = pipeline.steps.last().and_then(|x| x.job);
+                match last.and_then(|x| self.workers.get(&x)).map(|x| &x.state) {
+                    Some(JobState::Finished { output, .. }) => Snapshot::Done(output.clone()),
+                    _ => Snapshot::Error(PollingError::NotExist),
+                }
+            }
+            PipelineStatus::Failed { error, .. } => Snapshot::Error(error.clone()),
+            PipelineStatus::Running => {
+                let step = &pipeline.steps[pipeline.current.min(pipeline.steps.len() - 1)];
+                match step.job.and_then(|x| self.workers.get(&x).map(|job| (x, job))) {
+                    Some((token, job)) if job.finish_time().is_none() => self.snapshot(token, job),
+                    _ if step.kind == StepKind::WaitSeed => Snapshot::Queued {
+                        position: 0,
+                        waiting: Some("waiting for the seed".to_string()),
+                    },
+                    _ => Snapshot::Queued {
+                        position: 0,
+                        waiting: Some("waiting for the next step".to_string()),
+                    },
+                }
+            }
+        }
+    }
+
+    fn pipeline_state(&self, token: JobToken) -> PollingState {
+        match self.pipelines.get(&token).map(|x| self.pipeline_snapshot(x)) {
+            Some(Snapshot::Done(output)) => output.state(),
+            Some(Snapshot::Error(error)) => PollingState::Error(error),
+            _ => PollingState::Error(PollingError::NotFinished),
+        }
+    }
+
+    fn publish_pipeline(&self, token: JobToken) {
+        if let Some(pipeline) = self.pipelines.get(&token) {
+            self.registry
+                .publish(token, &pipeline.owner, self.pipeline_snapshot(pipeline));
+        }
+    }
+
+    fn record(&self, entry: &JournalEntry) {
+        if let Err(e) = self.journal.lock().append(entry) {
+            error!("write journal failed: {:?}", e);
+        }
+    }
+}
+
+/// drop the key of a job, unless a later submission already holds it
+fn release_key(keys: &mut HashMap<String, JobToken>, meta: &JobMeta, token: JobToken) {
+    if let Some(key) = meta.index_key() {
+        if keys.get(&key) == Some(&token) {
+            keys.remove(&key);
+        }
+    }
+}
+
+/// store the request of a new job and journal its submission
+fn persist(token: JobToken, request: &JobRequest, meta: &JobMeta, journal: &Mutex<Journal>) {
+    if let Err(e) = request.save(token) {
+        warn!("store request of job {} failed, it can not be retried: {:?}", token, e);
+    }
+
+    let entry = JournalEntry::Submitted {
+        token,
+        meta: meta.clone(),
+    };
+    if let Err(e) = journal.lock().append(&entry) {
+        error!("write journal failed: {:?}", e);
+    }
+}
+
+/// body of a job thread, runs the worker and records its result
+fn run_job(
+    token: JobToken,
+    request: &JobRequest,
+    control: &Arc<JobControl>,
+    cores: Option<&[usize]>,
+    journal: &Mutex<Journal>,
+    tx: Sender<Result<JobOutput, PollingError>>,
+) {
+    let r = run_in_worker(token, request, control, cores);
+
+    // removed jobs are already out of the journal
+    if control.is_cancelled() {
+        return;
+    }
+
+    let entry = match r.map(JobOutput::into_parts) {
+        Ok((result, stored)) => JournalEntry::Finished {
+            token,
+            result,
+            stored,
+            time: unix_now(),
+        },
+        Err(error) => JournalEntry::Failed {
+            token,
+            error,
+            time: unix_now(),
+        },
+    };
+
+    // record the result before handing it out, so it survives a restart
+    if let Err(e) = journal.lock().append(&entry) {
+        error!("record job {} failed: {:?}", token, e);
+    }
+
+    let r = match entry {
+        JournalEntry::Finished { result, stored, .. } => Ok(JobOutput::from_parts(result, stored)),
+        JournalEntry::Failed { error, .. } => Err(error),
+        _ => unreachable!(),
+    };
+    if let Err(e) = tx.send(r) {
+        error!("{:?}", e);
+    }
+}
+
+/// a job as recorded in the journal
+struct ReplayedJob {
+    meta: JobMeta,
+    start_time: Option<u64>,
+    /// `Finished`, `Failed` or `Interrupted`
+    terminal: Option<JournalEntry>,
+    callback: Option<CallbackStatus>,
+}
+
+pub struct RemovedJob {
+    /// thread of a job that was still running
+    handle: Option<JoinHandle<()>>,
+    request: Option<Arc<JobRequest>>,
+    target_len: Option<u64>,
+}
+
+impl RemovedJob {
+    /// wait for the killed worker to exit and clean up its partial outputs
+    pub fn finish(self) -> PollingState {
+        let was_running = self.handle.is_some();
+
+        if let Some(handle) = self.handle {
+            if handle.join().is_err() {
+                warn!("job thread panicked");
+            }
+        }
+
+        let cleanup = match (was_running, &self.request) {
+            (true, Some(request)) => request.cleanup(self.target_len).map_err(|e| format!("{:?}", e)),
+            _ => Ok(()),
+        };
+
+        PollingState::Removed { was_running, cleanup }
+    }
+}
+
+/// start queued jobs whenever a running one exits
+pub fn spawn_scheduler(state: Arc<Mutex<ServState>>) -> JoinHandle<()> {
+    let events = state.lock().events_rx.take().expect("scheduler already started");
+
+    thread::spawn(move || {
+        for _token in events.iter() {
+            ServState::schedule(&state);
+        }
+    })
+}
+
+pub fn spawn_sweeper(state: Arc<Mutex<ServState>>) -> JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(CONFIG.sweep_interval);
+
+        state.lock().sweep();
+        // memory or disk freed outside of the server may let queued jobs start
+        ServState::schedule(&state);
+    })
+}
+
+/// progress reported by the worker, or estimated from `average` seconds of past runs where it reports none
+pub fn estimate_progress(control: &JobControl, start_time: Option<u64>, average: Option<u64>) -> JobProgress {
+    let progress = control.progress();
+    let elapsed = start_time.map(|x| unix_now().saturating_sub(x)).unwrap_or(0);
+
+    let (percent, eta) = match (progress.percent, average) {
+        (Some(percent), _) if percent > 0.0 => {
+            let eta = elapsed as f64 * (100.0 - percent) / percent;
+            (Some(percent), Some(eta as u64))
+        }
+        (_, Some(average)) => {
+            let percent = (elapsed as f64 * 100.0 / average as f64).min(99.0);
+            (Some(percent), Some(average.saturating_sub(elapsed)))
+        }
+        _ => (progress.percent, None),
+    };
+
+    JobProgress {
+        phase: progress.phase.unwrap_or_else(|| "running".to_owned()),
+        percent,
+        elapsed,
+        eta,
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
 }
@@ -1,9 +1,18 @@
-use actix_web::web::Json;
+use std::sync::Arc;
+
+use actix_web::web::{Data, Json, Query};
 use actix_web::{HttpRequest, HttpResponse};
 use filecoin_proofs_api::post;
 use log::trace;
+use parking_lot::Mutex;
 
+use crate::api_error::{respond, respond_state, ApiError};
+use crate::auth::ClientId;
+use crate::jobs::JobRequest;
+use crate::polling::*;
 use crate::post_data::*;
+use crate::resources::Estimate;
+use crate::validate::{post_kind, Validate};
 
 pub async fn generate_winning_post_sector_challenge(
     _req: HttpRequest,
@@ -11,6 +20,10 @@ pub async fn generate_winning_post_sector_challenge(
 ) -> HttpResponse {
     trace!("generate_winning_post_sector_challenge: {:?}", data);
 
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
     let r = post::generate_winning_post_sector_challenge(
         data.proof_type,
         &data.randomness,
@@ -18,24 +31,57 @@ pub async fn generate_winning_post_sector_challenge(
         data.prover_id,
     );
 
-    let response = r.map_err(|e| format!("{:?}", e));
+    let response = r.map_err(ApiError::from);
     trace!("generate_winning_post_sector_challenge finish: {:?}", response);
-    HttpResponse::Ok().json(response)
+    respond(response)
 }
 
 pub async fn generate_winning_post(_req: HttpRequest, data: Json<GenerateWinningPostData>) -> HttpResponse {
     trace!("generate_winning_post: {:?}", data);
 
+    if let Err(e) = data.validate().and_then(|_| post_kind(&data, true)) {
+        return e.into();
+    }
+
+    if let Err(e) = data.check_capacity() {
+        return e.into();
+    }
+
     let r = post::generate_winning_post(&data.randomness, &data.replicas.as_object(), data.prover_id);
 
-    let response = r.map_err(|e| format!("{:?}", e));
+    let response = r.map_err(ApiError::from);
     trace!("generate_winning_post finish: {:?}", response);
-    HttpResponse::Ok().json(response)
+    respond(response)
+}
+
+pub async fn generate_winning_post_async(
+    state: Data<Arc<Mutex<ServState>>>,
+    options: Query<JobOptions>,
+    client: ClientId,
+    data: Json<GenerateWinningPostData>,
+) -> HttpResponse {
+    trace!("generate_winning_post_async: {:?}", data);
+
+    if let Err(e) = data.validate().and_then(|_| post_kind(&data, true)) {
+        return e.into();
+    }
+
+    let response = ServState::enqueue(
+        &state,
+        JobRequest::GenerateWinningPost(data.into_inner()),
+        options.into_inner(),
+        &client,
+    );
+    respond_state(response)
 }
 
 pub async fn verify_winning_post(_req: HttpRequest, data: Json<VerifyWinningPostData>) -> HttpResponse {
     trace!("verify_winning_post: {:?}", data);
 
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
     let r = post::verify_winning_post(
         &data.randomness,
         &data.proof,
@@ -43,24 +89,57 @@ pub async fn verify_winning_post(_req: HttpRequest, data: Json<VerifyWinningPost
         data.prover_id,
     );
 
-    let response = r.map_err(|e| format!("{:?}", e));
+    let response = r.map_err(ApiError::from);
     trace!("verify_winning_post finish: {:?}", response);
-    HttpResponse::Ok().json(response)
+    respond(response)
 }
 
 pub async fn generate_window_post(_req: HttpRequest, data: Json<GenerateWindowPostData>) -> HttpResponse {
     trace!("generate_window_post: {:?}", data);
 
+    if let Err(e) = data.validate().and_then(|_| post_kind(&data, false)) {
+        return e.into();
+    }
+
+    if let Err(e) = data.check_capacity() {
+        return e.into();
+    }
+
     let r = post::generate_window_post(&data.randomness, &data.replicas.as_object(), data.prover_id);
 
-    let response = r.map_err(|e| format!("{:?}", e));
+    let response = r.map_err(ApiError::from);
     trace!("generate_window_post finish: {:?}", response);
-    HttpResponse::Ok().json(response)
+    respond(response)
+}
+
+pub async fn generate_window_post_async(
+    state: Data<Arc<Mutex<ServState>>>,
+    options: Query<JobOptions>,
+    client: ClientId,
+    data: Json<GenerateWindowPostData>,
+) -> HttpResponse {
+    trace!("generate_window_post_async: {:?}", data);
+
+    if let Err(e) = data.validate().and_then(|_| post_kind(&data, false)) {
+        return e.into();
+    }
+
+    let response = ServState::enqueue(
+        &state,
+        JobRequest::GenerateWindowPost(data.into_inner()),
+        options.into_inner(),
+        &client,
+    );
+    respond_state(response)
 }
 
 pub async fn verify_window_post(_req: HttpRequest, data: Json<VerifyWindowPostData>) -> HttpResponse {
     trace!("verify_window_post: {:?}", data);
 
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
     let proofs: Vec<_> = data.proof.iter().map(|(x, y)| (*x, y.as_slice())).collect();
 
     let r = post::verify_window_post(
@@ -70,7 +149,7 @@ pub async fn verify_window_post(_req: HttpRequest, data: Json<VerifyWindowPostDa
         data.prover_id,
     );
 
-    let response = r.map_err(|e| format!("{:?}", e));
+    let response = r.map_err(ApiError::from);
     trace!("verify_window_post finish: {:?}", response);
-    HttpResponse::Ok().json(response)
+    respond(response)
 }
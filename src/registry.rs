@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use parking_lot::RwLock;
+
+use crate::auth::ClientId;
+use crate::jobs::{JobControl, JobOutput};
+use crate::polling::{estimate_progress, JobToken, PollingError, PollingState};
+
+const SHARDS: usize = 32;
+
+/// State of a job as last published by the scheduler
+#[derive(Clone)]
+pub enum Snapshot {
+    Queued {
+        position: usize,
+        waiting: Option<String>,
+    },
+    /// progress is read from the worker on every query
+    Running {
+        control: Arc<JobControl>,
+        start_time: u64,
+        /// seconds of past runs of the same kind
+        average: Option<u64>,
+    },
+    Done(JobOutput),
+    Error(PollingError),
+    /// tombstone of a dropped result
+    Expired,
+}
+
+impl Snapshot {
+    fn is_pending(&self) -> bool {
+        match self {
+            Snapshot::Queued { .. } | Snapshot::Running { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+struct Entry {
+    owner: Option<String>,
+    snapshot: Snapshot,
+    /// woken on every update while the job is pending
+    watchers: Vec<UnboundedSender<()>>,
+}
+
+/// Job states sharded by token, so polling clients neither wait for the scheduler nor for each other
+///
+/// The `parking_lot` locks are not poisoned, a panic while one is held does not take the API down.
+pub struct JobRegistry {
+    shards: Vec<RwLock<HashMap<JobToken, Entry>>>,
+}
+
+impl JobRegistry {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+        }
+    }
+
+    fn shard(&self, token: JobToken) -> &RwLock<HashMap<JobToken, Entry>> {
+        &self.shards[token.shard(SHARDS)]
+    }
+
+    /// replace the state of the job and wake its watchers
+    pub fn publish(&self, token: JobToken, owner: &Option<String>, snapshot: Snapshot) {
+        let mut shard = self.shard(token).write();
+        let entry = shard.entry(token).or_insert_with(|| Entry {
+            owner: owner.clone(),
+            snapshot: Snapshot::Expired,
+            watchers: vec![],
+        });
+        entry.snapshot = snapshot;
+
+        if entry.snapshot.is_pending() {
+            entry.watchers.retain(|x| x.unbounded_send(()).is_ok());
+        } else {
+            for watcher in entry.watchers.drain(..) {
+                let _ = watcher.unbounded_send(());
+            }
+        }
+    }
+
+    pub fn expire(&self, token: JobToken) {
+        self.publish(token, &None, Snapshot::Expired);
+    }
+
+    /// forget the job, watchers see their stream end
+    pub fn remove(&self, token: JobToken) {
+        if let Some(entry) = self.shard(token).write().remove(&token) {
+            for watcher in entry.watchers {
+                let _ = watcher.unbounded_send(());
+            }
+        }
+    }
+
+    /// jobs of other clients look like they do not exist, tombstones are visible to everyone
+    pub fn get(&self, token: JobToken, client: &ClientId) -> PollingState {
+        let shard = self.shard(token).read();
+        let entry = match shard.get(&token) {
+            Some(entry) => entry,
+            None => return PollingState::Error(PollingError::NotExist),
+        };
+
+        match &entry.snapshot {
+            Snapshot::Expired => PollingState::Expired,
+            _ if !client.can_access(&entry.owner) => PollingState::Error(PollingError::NotExist),
+            Snapshot::Queued { position, waiting } => PollingState::Queued {
+                position: *position,
+                waiting: waiting.clone(),
+            },
+            Snapshot::Running {
+                control,
+                start_time,
+                average,
+            } => PollingState::Running(estimate_progress(control, Some(*start_time), *average)),
+            Snapshot::Done(output) => output.state(),
+            Snapshot::Error(error) => PollingState::Error(error.clone()),
+        }
+    }
+
+    /// wakes on every transition and ends once the job has left the pending states
+    pub fn watch(&self, token: JobToken, client: &ClientId) -> UnboundedReceiver<()> {
+        let (tx, rx) = unbounded();
+
+        let mut shard = self.shard(token).write();
+        if let Some(entry) = shard.get_mut(&token) {
+            if client.can_access(&entry.owner) && entry.snapshot.is_pending() {
+                entry.watchers.push(tx);
+            }
+        }
+
+        rx
+    }
+}
+
+impl Default for JobRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
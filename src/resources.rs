@@ -0,0 +1,195 @@
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::api_error::{ApiError, ErrorCode};
+use crate::config::CONFIG;
+
+const GIB: f64 = (1u64 << 30) as f64;
+
+/// Estimated peak memory and disk usage of a job
+#[derive(Clone, Debug, Default)]
+pub struct Requirements {
+    pub memory: u64,
+    /// bytes written below each path
+    pub disk: Vec<(PathBuf, u64)>,
+}
+
+impl Requirements {
+    pub fn memory(memory: u64) -> Self {
+        Self { memory, disk: vec![] }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.memory == 0 && self.disk.is_empty()
+    }
+
+    pub fn with_disk<P: AsRef<Path>>(mut self, path: P, bytes: u64) -> Self {
+        self.disk.push((path.as_ref().to_owned(), bytes));
+        self
+    }
+}
+
+/// Operation whose needs are checked before it runs, in a worker or inline
+pub trait Estimate {
+    fn requirements(&self) -> Requirements;
+
+    /// `InsufficientResources` if it could never run here, the sync handlers check this like `enqueue` does
+    fn check_capacity(&self) -> Result<(), ApiError> {
+        match check_capacity(&self.requirements()) {
+            Some(reason) => Err(ApiError::new(ErrorCode::InsufficientResources, reason)),
+            None => Ok(()),
+        }
+    }
+}
+
+struct DiskSpace {
+    device: u64,
+    total: u64,
+    free: u64,
+}
+
+/// why the job can never run on this machine, `None` if it fits an idle server
+pub fn check_capacity(need: &Requirements) -> Option<String> {
+    if !CONFIG.admission {
+        return None;
+    }
+
+    match mem_info() {
+        Ok((total, _)) if need.memory > total => {
+            return Some(format!(
+                "needs {:.1} GiB memory, the server has {:.1} GiB",
+                need.memory as f64 / GIB,
+                total as f64 / GIB
+            ))
+        }
+        Ok(_) => {}
+        Err(e) => warn!("read memory info failed: {:?}", e),
+    }
+
+    for (path, bytes) in need.disk.iter() {
+        match disk_space(path) {
+            Ok(space) if *bytes > space.total => {
+                return Some(format!(
+                    "needs {:.1} GiB disk at {:?}, the filesystem has {:.1} GiB",
+                    *bytes as f64 / GIB,
+                    path,
+                    space.total as f64 / GIB
+                ))
+            }
+            Ok(_) => {}
+            Err(e) => warn!("read disk space of {:?} failed: {:?}", path, e),
+        }
+    }
+
+    None
+}
+
+/// why the job can not start next to the `running` jobs right now, `None` if it fits
+///
+/// Running jobs are counted with their full estimate, even if they have not used all of it yet.
+pub fn check_available(need: &Requirements, running: &[Requirements]) -> Option<String> {
+    // a job without needs is never held, however full the server is
+    if !CONFIG.admission || need.is_empty() {
+        return None;
+    }
+
+    match mem_info() {
+        Ok((total, available)) => {
+            let reserved: u64 = running.iter().map(|x| x.memory).sum();
+            if need.memory > available || need.memory + reserved > total {
+                return Some(format!(
+                    "needs {:.1} GiB memory, {:.1} GiB available, {:.1} GiB reserved by running jobs",
+                    need.memory as f64 / GIB,
+                    available as f64 / GIB,
+                    reserved as f64 / GIB
+                ));
+            }
+        }
+        Err(e) => warn!("read memory info failed: {:?}", e),
+    }
+
+    for (path, bytes) in need.disk.iter() {
+        let space = match disk_space(path) {
+            Ok(space) => space,
+            Err(e) => {
+                warn!("read disk space of {:?} failed: {:?}", path, e);
+                continue;
+            }
+        };
+
+        let reserved: u64 = running
+            .iter()
+            .flat_map(|x| x.disk.iter())
+            .filter(|(path, _)| disk_space(path).map(|x| x.device == space.device).unwrap_or(false))
+            .map(|(_, bytes)| bytes)
+            .sum();
+        if bytes + reserved > space.free {
+            return Some(format!(
+                "needs {:.1} GiB disk at {:?}, {:.1} GiB free, {:.1} GiB reserved by running jobs",
+                *bytes as f64 / GIB,
+                path,
+                space.free as f64 / GIB,
+                reserved as f64 / GIB
+            ));
+        }
+    }
+
+    None
+}
+
+/// `MemTotal` and `MemAvailable` in bytes
+fn mem_info() -> io::Result<(u64, u64)> {
+    let reader = BufReader::new(File::open("/proc/meminfo")?);
+
+    let mut total = None;
+    let mut available = None;
+    for line in reader.lines() {
+        let line = line?;
+        let mut parts = line.split_whitespace();
+        let value = match (parts.next(), parts.next().and_then(|x| x.parse::<u64>().ok())) {
+            (Some(name), Some(kb)) => (name, kb * 1024),
+            _ => continue,
+        };
+
+        match value {
+            ("MemTotal:", bytes) => total = Some(bytes),
+            ("MemAvailable:", bytes) => available = Some(bytes),
+            _ => {}
+        }
+    }
+
+    match (total, available) {
+        (Some(total), Some(available)) => Ok((total, available)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no MemTotal or MemAvailable",
+        )),
+    }
+}
+
+/// space of the filesystem `path` is on, or will be on once created
+fn disk_space(path: &Path) -> io::Result<DiskSpace> {
+    let existing = path
+        .ancestors()
+        .find(|x| x.exists())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{:?}", path)))?;
+
+    let c_path = CString::new(existing.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(DiskSpace {
+        device: fs::metadata(existing)?.dev(),
+        total: stat.f_blocks as u64 * stat.f_frsize as u64,
+        free: stat.f_bavail as u64 * stat.f_frsize as u64,
+    })
+}
@@ -1,33 +1,70 @@
-use std::fs::OpenOptions;
-use std::io;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
 use std::path::Path;
-use std::sync::mpsc::channel;
-use std::sync::{Arc, Mutex};
-use std::thread::{self, JoinHandle};
+use std::sync::Arc;
+use std::thread;
 
-use actix_web::web::{Data, Json, Payload};
+use actix_multipart::Multipart;
+use actix_web::http::header;
+use actix_web::web::{self, Bytes, Data, Json, Payload, Query};
 use actix_web::{Error, HttpRequest, HttpResponse};
 use bytes::BytesMut;
 use filecoin_proofs_api::{seal, PieceInfo};
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::future::{self, Either};
+use futures::stream::{self, Stream, TryStreamExt};
 use futures_util::StreamExt;
-use log::{error, trace};
+use log::{trace, warn};
+use parking_lot::Mutex;
 use serde_json::json;
 
+use crate::api_error::{respond, respond_state, ApiError, ErrorCode};
+use crate::auth::ClientId;
+use crate::jobs::JobRequest;
+use crate::pipeline::{PipelineData, PipelineSeed};
 use crate::polling::*;
+use crate::registry::JobRegistry;
+use crate::resources::Estimate;
 use crate::seal_data::*;
+use crate::sectors::SectorStore;
+use crate::staged::{self, BodyPipe};
 use crate::types::WebPieceInfo;
+use crate::validate::{commit_sector, Validate, ValidationErrors};
 
-pub async fn clear_cache(_req: HttpRequest, data: Json<ClearCacheData>) -> HttpResponse {
+pub async fn clear_cache(
+    _req: HttpRequest,
+    sectors: Data<Arc<Mutex<SectorStore>>>,
+    data: Json<ClearCacheData>,
+) -> HttpResponse {
     trace!("clear_cache");
 
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
     let r = seal::clear_cache(data.sector_size, Path::new(&data.cache_path));
 
-    HttpResponse::Ok().json(r.map_err(|e| format!("{:?}", e)))
+    let r = r.map_err(ApiError::from);
+    sectors.lock().finalize(&data.cache_path, &json!(r));
+    respond(r)
 }
 
-pub async fn seal_pre_commit_phase1(data: Json<SealPreCommitPhase1Data>) -> HttpResponse {
+pub async fn seal_pre_commit_phase1(
+    sectors: Data<Arc<Mutex<SectorStore>>>,
+    client: ClientId,
+    data: Json<SealPreCommitPhase1Data>,
+) -> HttpResponse {
     trace!("seal_pre_commit_phase1");
 
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
+    if let Err(e) = data.check_capacity() {
+        return e.into();
+    }
+
     let piece_infos: Vec<PieceInfo> = data.piece_infos.iter().map(|x| x.as_object()).collect();
 
     let r = seal::seal_pre_commit_phase1(
@@ -41,57 +78,121 @@ pub async fn seal_pre_commit_phase1(data: Json<SealPreCommitPhase1Data>) -> Http
         &piece_infos[..],
     );
 
-    HttpResponse::Ok().json(r.map_err(|e| format!("{:?}", e)))
+    let r = r.map_err(ApiError::from);
+    let request = JobRequest::SealPreCommitPhase1(data.into_inner());
+    sectors
+        .lock()
+        .observe(&request, &json!(r), None, &Some(client.name.clone()));
+    respond(r)
+}
+
+pub async fn seal_pre_commit_phase1_async(
+    state: Data<Arc<Mutex<ServState>>>,
+    options: Query<JobOptions>,
+    client: ClientId,
+    data: Json<SealPreCommitPhase1Data>,
+) -> HttpResponse {
+    trace!("seal_pre_commit_phase1_async: {:?}", data);
+
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
+    let response = ServState::enqueue(
+        &state,
+        JobRequest::SealPreCommitPhase1(data.into_inner()),
+        options.into_inner(),
+        &client,
+    );
+    respond_state(response)
 }
 
-pub async fn seal_pre_commit_phase2(data: Json<SealPreCommitPhase2Data>) -> HttpResponse {
+pub async fn seal_pre_commit_phase2(
+    sectors: Data<Arc<Mutex<SectorStore>>>,
+    client: ClientId,
+    data: Json<SealPreCommitPhase2Data>,
+) -> HttpResponse {
     trace!("seal_pre_commit_phase2");
 
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
+    if let Err(e) = data.check_capacity() {
+        return e.into();
+    }
+
     let r = seal::seal_pre_commit_phase2(data.phase1_output.clone(), &data.cache_path, &data.out_path);
 
-    HttpResponse::Ok().json(r.map_err(|e| format!("{:?}", e)))
+    let r = r.map_err(ApiError::from);
+    let request = JobRequest::SealPreCommitPhase2(data.into_inner());
+    sectors
+        .lock()
+        .observe(&request, &json!(r), None, &Some(client.name.clone()));
+    respond(r)
+}
+
+pub async fn seal_pre_commit_phase2_async(
+    state: Data<Arc<Mutex<ServState>>>,
+    options: Query<JobOptions>,
+    client: ClientId,
+    data: Json<SealPreCommitPhase2Data>,
+) -> HttpResponse {
+    trace!("seal_pre_commit_phase2_async: {:?}", data);
+
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
+    let response = ServState::enqueue(
+        &state,
+        JobRequest::SealPreCommitPhase2(data.into_inner()),
+        options.into_inner(),
+        &client,
+    );
+    respond_state(response)
 }
 
 pub async fn compute_comm_d(data: Json<ComputeCommDData>) -> HttpResponse {
     trace!("compute_comm_d");
 
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
     let piece_infos: Vec<PieceInfo> = data.piece_infos.iter().map(|x| x.as_object()).collect();
 
     let r = seal::compute_comm_d(data.registered_proof, &piece_infos[..]);
 
-    HttpResponse::Ok().json(r.map_err(|e| format!("{:?}", e)))
+    respond(r.map_err(ApiError::from))
 }
 
-pub async fn seal_commit_phase1(state: Data<Arc<Mutex<ServState>>>, data: Json<SealCommitPhase1Data>) -> HttpResponse {
+pub async fn seal_commit_phase1(
+    state: Data<Arc<Mutex<ServState>>>,
+    options: Query<JobOptions>,
+    client: ClientId,
+    data: Json<SealCommitPhase1Data>,
+) -> HttpResponse {
     trace!("seal_commit_phase1: {:?}", data);
 
-    let (tx, rx) = channel();
-    let handle: JoinHandle<()> = thread::spawn(move || {
-        let piece_infos: Vec<PieceInfo> = data.piece_infos.iter().map(|x| x.as_object()).collect();
-
-        let r = seal::seal_commit_phase1(
-            &data.cache_path,
-            &data.replica_path,
-            data.prover_id,
-            data.sector_id,
-            data.ticket,
-            data.seed,
-            data.pre_commit.clone(),
-            &piece_infos[..],
-        );
-
-        trace!("seal_commit_phase1 finished: {:?}", r);
-        if let Err(e) = tx.send(json!(r.map_err(|e| format!("{:?}", e)))) {
-            error!("{:?}", e);
-        }
-    });
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
 
-    let response = state.lock().unwrap().enqueue(handle, rx);
-    HttpResponse::Ok().json(response)
+    let response = ServState::enqueue(
+        &state,
+        JobRequest::SealCommitPhase1(data.into_inner()),
+        options.into_inner(),
+        &client,
+    );
+    respond_state(response)
 }
 
 pub async fn seal_commit_phase2(
     state: Data<Arc<Mutex<ServState>>>,
+    sectors: Data<Arc<Mutex<SectorStore>>>,
+    options: Query<JobOptions>,
+    client: ClientId,
     mut payload: Payload,
 ) -> Result<HttpResponse, Error> {
     let mut bytes = BytesMut::new();
@@ -99,26 +200,32 @@ pub async fn seal_commit_phase2(
         bytes.extend_from_slice(&item?);
     }
 
-    let data: SealCommitPhase2Data = serde_json::from_slice(bytes.as_ref())?;
+    let data: SealCommitPhase2Data = match serde_json::from_slice(bytes.as_ref()) {
+        Ok(data) => data,
+        Err(e) => return Ok(ValidationErrors::body(e).into()),
+    };
     trace!("seal_commit_phase2: {:?}", data);
 
-    let (tx, rx) = channel();
-    let handle: JoinHandle<()> = thread::spawn(move || {
-        let r = seal::seal_commit_phase2(data.phase1_output.clone(), data.prover_id, data.sector_id);
-
-        trace!("seal_commit_phase2 finished: {:?}", r);
-        if let Err(e) = tx.send(json!(r.map_err(|e| format!("{:?}", e)))) {
-            error!("{:?}", e);
-        }
-    });
+    if let Err(e) = data.validate().and_then(|_| commit_sector(&data, &sectors.lock())) {
+        return Ok(e.into());
+    }
 
-    let response = state.lock().unwrap().enqueue(handle, rx);
-    Ok(HttpResponse::Ok().json(response))
+    let response = ServState::enqueue(
+        &state,
+        JobRequest::SealCommitPhase2(data),
+        options.into_inner(),
+        &client,
+    );
+    Ok(respond_state(response))
 }
 
 pub async fn verify_seal(data: Json<VerifySealData>) -> HttpResponse {
     trace!("verify_seal");
 
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
     let r = seal::verify_seal(
         data.registered_proof,
         data.comm_r_in,
@@ -130,12 +237,16 @@ pub async fn verify_seal(data: Json<VerifySealData>) -> HttpResponse {
         data.proof_vec.as_slice(),
     );
 
-    HttpResponse::Ok().json(r.map_err(|e| format!("{:?}", e)))
+    respond(r.map_err(ApiError::from))
 }
 
 pub async fn verify_batch_seal(data: Json<VerifyBatchSealData>) -> HttpResponse {
     trace!("verify_batch_seal");
 
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
     let proof_vecs: Vec<_> = data.proof_vecs.iter().map(|x| x.as_slice()).collect();
 
     let r = seal::verify_batch_seal(
@@ -149,12 +260,20 @@ pub async fn verify_batch_seal(data: Json<VerifyBatchSealData>) -> HttpResponse
         proof_vecs.as_slice(),
     );
 
-    HttpResponse::Ok().json(r.map_err(|e| format!("{:?}", e)))
+    respond(r.map_err(ApiError::from))
 }
 
 pub async fn get_unsealed_range(data: Json<GetUnsealedRangeData>) -> HttpResponse {
     trace!("get_unsealed_range");
 
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
+    if let Err(e) = data.check_capacity() {
+        return e.into();
+    }
+
     let r = seal::get_unsealed_range(
         data.registered_proof,
         &data.cache_path,
@@ -168,46 +287,418 @@ pub async fn get_unsealed_range(data: Json<GetUnsealedRangeData>) -> HttpRespons
         data.num_bytes,
     );
 
-    HttpResponse::Ok().json(r.map_err(|e| format!("{:?}", e)))
+    respond(r.map_err(ApiError::from))
+}
+
+pub async fn get_unsealed_range_async(
+    state: Data<Arc<Mutex<ServState>>>,
+    options: Query<JobOptions>,
+    client: ClientId,
+    data: Json<GetUnsealedRangeData>,
+) -> HttpResponse {
+    trace!("get_unsealed_range_async: {:?}", data);
+
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
+    let response = ServState::enqueue(
+        &state,
+        JobRequest::GetUnsealedRange(data.into_inner()),
+        options.into_inner(),
+        &client,
+    );
+    respond_state(response)
 }
 
-pub async fn generate_piece_commitment(data: Json<GeneratePieceCommitmentData>) -> io::Result<HttpResponse> {
+pub async fn generate_piece_commitment(data: Json<GeneratePieceCommitmentData>) -> HttpResponse {
     trace!("generate_piece_commitment");
 
-    let source = OpenOptions::new().read(true).open(&data.source)?;
-    let r = seal::generate_piece_commitment(data.registered_proof, source, data.piece_size);
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
+    if let Err(e) = data.check_capacity() {
+        return e.into();
+    }
+
+    let r = File::open(&data.source).map_err(ApiError::from).and_then(|source| {
+        seal::generate_piece_commitment(data.registered_proof, source, data.piece_size).map_err(ApiError::from)
+    });
+
+    respond(r.map(WebPieceInfo::from_object))
+}
 
-    Ok(HttpResponse::Ok().json(r.map(|x| WebPieceInfo::from_object(x)).map_err(|e| format!("{:?}", e))))
+pub async fn generate_piece_commitment_async(
+    state: Data<Arc<Mutex<ServState>>>,
+    options: Query<JobOptions>,
+    client: ClientId,
+    data: Json<GeneratePieceCommitmentData>,
+) -> HttpResponse {
+    trace!("generate_piece_commitment_async: {:?}", data);
+
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
+    let response = ServState::enqueue(
+        &state,
+        JobRequest::GeneratePieceCommitment(data.into_inner()),
+        options.into_inner(),
+        &client,
+    );
+    respond_state(response)
 }
 
-pub async fn add_piece(data: Json<AddPieceData>) -> io::Result<HttpResponse> {
+pub async fn add_piece(
+    sectors: Data<Arc<Mutex<SectorStore>>>,
+    client: ClientId,
+    data: Json<AddPieceData>,
+) -> HttpResponse {
     trace!("add_piece");
 
-    let source = OpenOptions::new().read(true).open(&data.source)?;
-    let target = OpenOptions::new().write(true).open(&data.target)?;
-    let r = seal::add_piece(
-        data.registered_proof,
-        source,
-        target,
-        data.piece_size,
-        &data.piece_lengths[..],
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
+    if let Err(e) = data.check_capacity() {
+        return e.into();
+    }
+
+    let r = staged::add_piece(&data);
+
+    let request = JobRequest::AddPiece(data.into_inner());
+    sectors
+        .lock()
+        .observe(&request, &json!(r), None, &Some(client.name.clone()));
+    respond(r)
+}
+
+pub async fn add_piece_async(
+    state: Data<Arc<Mutex<ServState>>>,
+    options: Query<JobOptions>,
+    client: ClientId,
+    data: Json<AddPieceData>,
+) -> HttpResponse {
+    trace!("add_piece_async: {:?}", data);
+
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
+    let response = ServState::enqueue(
+        &state,
+        JobRequest::AddPiece(data.into_inner()),
+        options.into_inner(),
+        &client,
     );
+    respond_state(response)
+}
+
+/// add a piece sent as the request body, raw or as the first part of a multipart form, without storing it first
+///
+/// The body goes through a `BodyPipe` to an `add_piece` job, so it is scheduled and recorded like any other job,
+/// and the request waits for the job to answer with its `AddPieceOutput`.
+pub async fn add_piece_stream(
+    req: HttpRequest,
+    state: Data<Arc<Mutex<ServState>>>,
+    registry: Data<Arc<JobRegistry>>,
+    options: Query<JobOptions>,
+    client: ClientId,
+    query: Query<AddPieceStreamQuery>,
+    payload: Payload,
+) -> Result<HttpResponse, ApiError> {
+    trace!("add_piece_stream: {:?}", query);
+
+    if let Err(e) = query.validate() {
+        return Ok(e.into());
+    }
+
+    // the form around a multipart body makes it longer than the piece, so only a raw one is checked up front
+    let limit = u64::from(query.piece_size);
+    let multipart = is_multipart(&req);
+    if !multipart && content_length(&req).map(|x| x > limit).unwrap_or(false) {
+        return Err(too_large(limit));
+    }
+
+    let pipe = Arc::new(web::block(BodyPipe::create).await?);
+    let mut data = query.add_piece_data();
+    data.source = pipe.path().to_string_lossy().into_owned();
+
+    // the pipe has a single reader, a job key must not send the body to another job
+    let options = JobOptions {
+        job_key: None,
+        ..options.into_inner()
+    };
+    let token = match ServState::enqueue(&state, JobRequest::AddPiece(data), options, &client).into_result()? {
+        PollingState::Started(token) => token,
+        response => return Ok(respond_state(response)),
+    };
+    let mut job = StreamJob {
+        state: state.get_ref().clone(),
+        token,
+        client: client.clone(),
+        done: false,
+    };
+
+    // a job removed while queued never opens the pipe, which would leave the request hanging
+    let mut watcher = registry.watch(token, &client);
+    let opening = {
+        let pipe = pipe.clone();
+        web::block(move || pipe.open())
+    };
+    let target = match future::select(opening, Box::pin(ended(&mut watcher))).await {
+        Either::Left((target, _)) => Some(target?),
+        Either::Right((_, opening)) => {
+            pipe.release();
+            let _ = opening.await;
+            None
+        }
+    };
+
+    if let Some(target) = target {
+        if multipart {
+            let mut form = Multipart::new(req.headers(), payload);
+            match form.try_next().await {
+                Ok(Some(field)) => feed(field, target, limit).await?,
+                Ok(None) => feed(stream::empty::<Result<Bytes, Error>>(), target, limit).await?,
+                Err(e) => return Err(ApiError::new(ErrorCode::InvalidInput, e.to_string())),
+            }
+        } else {
+            feed(payload, target, limit).await?;
+        }
+        ended(&mut watcher).await;
+    }
+
+    job.done = true;
+    let response = match registry.get(token, &client) {
+        PollingState::Done(result) => {
+            let r: Result<AddPieceOutput, ApiError> = serde_json::from_value(result)
+                .unwrap_or_else(|e| Err(ApiError::new(ErrorCode::Internal, e.to_string())));
+            respond(r)
+        }
+        response => respond_state(response),
+    };
+
+    // the answer carries the result, the client has nothing left to poll for, a removed job is already gone
+    let _ = state.lock().ack(token, &client);
+
+    Ok(response)
+}
+
+/// Job of `/seal/add_piece_stream`, removed when the request ends before its answer, e.g. the client went away
+struct StreamJob {
+    state: Arc<Mutex<ServState>>,
+    token: JobToken,
+    client: ClientId,
+    done: bool,
+}
+
+impl Drop for StreamJob {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        warn!("add_piece_stream job {} lost its upload", self.token);
+        if let Ok(removed) = self.state.lock().remove(self.token, &self.client) {
+            // waiting for the worker to exit must not block the handler threads
+            thread::spawn(move || removed.finish());
+        }
+    }
+}
+
+/// wait until the job has left the pending states
+async fn ended(watcher: &mut UnboundedReceiver<()>) {
+    while watcher.next().await.is_some() {}
+}
+
+fn is_multipart(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.starts_with("multipart/"))
+        .unwrap_or(false)
+}
+
+fn content_length(req: &HttpRequest) -> Option<u64> {
+    req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse().ok())
+}
 
-    Ok(HttpResponse::Ok().json(
-        r.map(|(x, y)| AddPieceOutput::from_object((x, y)))
-            .map_err(|e| format!("{:?}", e)),
-    ))
+fn too_large(limit: u64) -> ApiError {
+    ApiError::new(
+        ErrorCode::InvalidInput,
+        format!("body is larger than the piece size {}", limit),
+    )
 }
 
-pub async fn write_and_preprocess(data: Json<WriteAndPreprocessData>) -> io::Result<HttpResponse> {
+/// write the chunks of `body` to the pipe, a body shorter than the piece is zero padded to its full size
+///
+/// Each chunk is checked before it is written, so nothing past the piece size reaches the target.
+async fn feed<S, E>(mut body: S, mut target: File, limit: u64) -> Result<(), ApiError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: fmt::Display,
+{
+    let mut total = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| ApiError::new(ErrorCode::InvalidInput, e.to_string()))?;
+        total += chunk.len() as u64;
+        if total > limit {
+            return Err(too_large(limit));
+        }
+
+        target = web::block(move || target.write_all(&chunk).map(|_| target)).await?;
+    }
+
+    let mut padding = io::repeat(0).take(limit - total);
+    web::block(move || io::copy(&mut padding, &mut target)).await?;
+
+    Ok(())
+}
+
+pub async fn write_and_preprocess(data: Json<WriteAndPreprocessData>) -> HttpResponse {
     trace!("write_and_preprocess");
 
-    let source = OpenOptions::new().read(true).open(&data.source)?;
-    let target = OpenOptions::new().write(true).open(&data.target)?;
-    let r = seal::write_and_preprocess(data.registered_proof, source, target, data.piece_size);
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
+    if let Err(e) = data.check_capacity() {
+        return e.into();
+    }
+
+    respond(staged::write_and_preprocess(&data))
+}
+
+pub async fn write_and_preprocess_async(
+    state: Data<Arc<Mutex<ServState>>>,
+    options: Query<JobOptions>,
+    client: ClientId,
+    data: Json<WriteAndPreprocessData>,
+) -> HttpResponse {
+    trace!("write_and_preprocess_async: {:?}", data);
+
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
+    let response = ServState::enqueue(
+        &state,
+        JobRequest::WriteAndPreprocess(data.into_inner()),
+        options.into_inner(),
+        &client,
+    );
+    respond_state(response)
+}
+
+/// write the pieces into a new staged file, the answer holds the `piece_infos` and comm_d for PC1
+pub async fn stage_sector(
+    sectors: Data<Arc<Mutex<SectorStore>>>,
+    client: ClientId,
+    data: Json<StageSectorData>,
+) -> HttpResponse {
+    trace!("stage_sector");
+
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
+    if let Err(e) = data.check_capacity() {
+        return e.into();
+    }
+
+    let r = staged::stage_sector(&data);
+
+    let request = JobRequest::StageSector(data.into_inner());
+    sectors
+        .lock()
+        .observe(&request, &json!(r), None, &Some(client.name.clone()));
+    respond(r)
+}
+
+pub async fn stage_sector_async(
+    state: Data<Arc<Mutex<ServState>>>,
+    options: Query<JobOptions>,
+    client: ClientId,
+    data: Json<StageSectorData>,
+) -> HttpResponse {
+    trace!("stage_sector_async: {:?}", data);
+
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
+    let response = ServState::enqueue(
+        &state,
+        JobRequest::StageSector(data.into_inner()),
+        options.into_inner(),
+        &client,
+    );
+    respond_state(response)
+}
+
+/// seal a whole sector, the response carries the pipeline token that `/sys/query_state` also takes
+pub async fn pipeline(
+    state: Data<Arc<Mutex<ServState>>>,
+    options: Query<JobOptions>,
+    client: ClientId,
+    data: Json<PipelineData>,
+) -> HttpResponse {
+    trace!("pipeline: {:?}", data);
+
+    if let Err(e) = data.validate() {
+        return e.into();
+    }
+
+    let response = ServState::enqueue_pipeline(&state, data.into_inner(), options.into_inner(), &client);
+    respond_state(response)
+}
+
+pub async fn pipeline_seed(
+    state: Data<Arc<Mutex<ServState>>>,
+    client: ClientId,
+    seed: Json<PipelineSeed>,
+) -> HttpResponse {
+    trace!("pipeline_seed: {:?}", seed);
+
+    let response = ServState::set_pipeline_seed(&state, seed.into_inner(), &client);
+    respond_state(response)
+}
 
-    Ok(HttpResponse::Ok().json(
-        r.map(|(x, y)| WriteAndPreprocessOutput::from_object((x, y)))
-            .map_err(|e| format!("{:?}", e)),
-    ))
+pub async fn pipeline_state(
+    state: Data<Arc<Mutex<ServState>>>,
+    token: Json<JobToken>,
+    client: ClientId,
+) -> HttpResponse {
+    trace!("pipeline_state");
+
+    let response = state
+        .lock()
+        .pipeline_info(*token, &client)
+        .ok_or(PollingError::NotExist)
+        .map_err(ApiError::from);
+    respond(response)
+}
+
+/// drop a pipeline with the outputs of its steps, a running step is cancelled
+pub async fn pipeline_remove(
+    state: Data<Arc<Mutex<ServState>>>,
+    token: Json<JobToken>,
+    client: ClientId,
+) -> HttpResponse {
+    trace!("pipeline_remove");
+
+    let removed = state.lock().remove_pipeline(*token, &client);
+
+    let response = match removed {
+        Ok(job) => web::block(move || Ok::<_, ()>(job.finish()))
+            .await
+            .unwrap_or_else(|e| PollingState::Error(PollingError::WorkerFailed(format!("{:?}", e)))),
+        Err(e) => PollingState::Error(e),
+    };
+    respond_state(response)
 }
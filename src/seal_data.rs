@@ -1,3 +1,5 @@
+use std::num::ParseIntError;
+
 use filecoin_proofs_api::seal::{SealCommitPhase1Output, SealPreCommitPhase1Output, SealPreCommitPhase2Output};
 use filecoin_proofs_api::{
     Commitment, PieceInfo, ProverId, RegisteredSealProof, SectorId, Ticket, UnpaddedByteIndex, UnpaddedBytesAmount,
@@ -110,8 +112,41 @@ pub struct AddPieceData {
     pub piece_lengths: Vec<UnpaddedBytesAmount>,
 }
 
+/// Query of `/seal/add_piece_stream`, the piece itself is the request body
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AddPieceStreamQuery {
+    pub registered_proof: RegisteredSealProof,
+    /// created if it does not exist, the piece is appended
+    pub target: String,
+    pub piece_size: UnpaddedBytesAmount,
+    /// comma separated sizes of the pieces already in `target`
+    #[serde(default)]
+    pub piece_lengths: String,
+}
+
+impl AddPieceStreamQuery {
+    pub fn piece_lengths(&self) -> Result<Vec<UnpaddedBytesAmount>, ParseIntError> {
+        self.piece_lengths
+            .split(',')
+            .filter(|x| !x.trim().is_empty())
+            .map(|x| x.trim().parse().map(UnpaddedBytesAmount))
+            .collect()
+    }
+
+    /// the same piece as an `add_piece` request, which has no source file
+    pub fn add_piece_data(&self) -> AddPieceData {
+        AddPieceData {
+            registered_proof: self.registered_proof,
+            source: String::new(),
+            target: self.target.clone(),
+            piece_size: self.piece_size,
+            piece_lengths: self.piece_lengths().unwrap_or_default(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct AddPieceOutput(WebPieceInfo, UnpaddedBytesAmount);
+pub struct AddPieceOutput(pub WebPieceInfo, pub UnpaddedBytesAmount);
 
 impl AddPieceOutput {
     pub fn from_object((piece_info, size): (PieceInfo, UnpaddedBytesAmount)) -> Self {
@@ -131,3 +166,28 @@ pub struct WriteAndPreprocessData {
 }
 
 pub type WriteAndPreprocessOutput = AddPieceOutput;
+
+/// A source file of a staged sector, zero padded when it is shorter than `piece_size`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StagePiece {
+    pub source: String,
+    pub piece_size: UnpaddedBytesAmount,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StageSectorData {
+    pub registered_proof: RegisteredSealProof,
+    /// created if it does not exist, an existing file is overwritten
+    pub target: String,
+    /// written in order, with zero pieces in between where a piece would not be aligned
+    pub pieces: Vec<StagePiece>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StageSectorOutput {
+    /// every piece of the sector including the zero pieces, the `piece_infos` of PC1
+    pub piece_infos: Vec<WebPieceInfo>,
+    /// index in `piece_infos` of each source piece
+    pub sources: Vec<usize>,
+    pub comm_d: Commitment,
+}
@@ -0,0 +1,388 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use filecoin_proofs_api::seal::SealPreCommitPhase2Output;
+use filecoin_proofs_api::{Commitment, ProverId, SectorId};
+use log::{error, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::api_error::ApiError;
+use crate::jobs::JobRequest;
+use crate::polling::{unix_now, JobToken};
+use crate::seal_data::{AddPieceOutput, StageSectorOutput};
+use crate::types::WebPieceInfo;
+
+const SECTOR_FILE: &str = "sectors.db";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SectorState {
+    AddPiece,
+    PreCommit1,
+    PreCommit2,
+    Commit1,
+    Commit2,
+    /// the cache was cleared after C2
+    Finalized,
+    Failed,
+}
+
+/// One step run on a sector, `error` is set when it failed
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Transition {
+    pub state: SectorState,
+    pub time: u64,
+    /// the async job that ran the step
+    pub job: Option<JobToken>,
+    pub error: Option<String>,
+}
+
+impl Transition {
+    fn new(state: SectorState, job: Option<JobToken>, error: Option<String>) -> Self {
+        Self {
+            state,
+            time: unix_now(),
+            job,
+            error,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SectorRecord {
+    pub prover_id: ProverId,
+    pub sector_id: SectorId,
+    /// name of the client that first worked on the sector
+    pub owner: Option<String>,
+    /// registered seal proof
+    pub proof: Option<String>,
+    /// `Failed` when the latest step failed
+    pub state: SectorState,
+    pub staged_path: Option<String>,
+    pub cache_path: Option<String>,
+    pub sealed_path: Option<String>,
+    pub pieces: Vec<WebPieceInfo>,
+    pub comm_d: Option<Commitment>,
+    pub comm_r: Option<Commitment>,
+    /// error of the latest step
+    pub error: Option<String>,
+    pub transitions: Vec<Transition>,
+}
+
+impl SectorRecord {
+    fn new(prover_id: ProverId, sector_id: SectorId, owner: &Option<String>) -> Self {
+        Self {
+            prover_id,
+            sector_id,
+            owner: owner.clone(),
+            proof: None,
+            state: SectorState::AddPiece,
+            staged_path: None,
+            cache_path: None,
+            sealed_path: None,
+            pieces: vec![],
+            comm_d: None,
+            comm_r: None,
+            error: None,
+            transitions: vec![],
+        }
+    }
+
+    fn push(&mut self, state: SectorState, job: Option<JobToken>, error: Option<String>) {
+        self.state = if error.is_some() { SectorState::Failed } else { state };
+        self.error = error.clone();
+        self.transitions.push(Transition::new(state, job, error));
+    }
+}
+
+/// Pieces added to a staged file before PC1 tells which sector it belongs to
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct Staged {
+    pieces: Vec<WebPieceInfo>,
+    transitions: Vec<Transition>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum SectorEntry {
+    Sector(SectorRecord),
+    Staged {
+        path: String,
+        staged: Staged,
+    },
+    /// the staged file was taken over by a sector
+    Unstaged {
+        path: String,
+    },
+}
+
+type SectorKey = (ProverId, SectorId);
+
+/// Sectors this server has worked on, fed by the seal handlers and the finished seal jobs
+///
+/// Every change appends the whole record to `sectors.db`, the file is compacted to one line per record on open.
+pub struct SectorStore {
+    path: PathBuf,
+    file: File,
+    sectors: BTreeMap<SectorKey, SectorRecord>,
+    /// staged files by path
+    staged: HashMap<String, Staged>,
+}
+
+impl SectorStore {
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(SECTOR_FILE);
+
+        let mut sectors = BTreeMap::new();
+        let mut staged = HashMap::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            for (n, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+
+                match serde_json::from_str(&line) {
+                    Ok(SectorEntry::Sector(record)) => {
+                        sectors.insert((record.prover_id, record.sector_id), record);
+                    }
+                    Ok(SectorEntry::Staged { path, staged: pieces }) => {
+                        staged.insert(path, pieces);
+                    }
+                    Ok(SectorEntry::Unstaged { path }) => {
+                        staged.remove(&path);
+                    }
+                    Err(e) => warn!("skip broken sector line {}: {:?}", n + 1, e),
+                }
+            }
+        }
+
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            for record in sectors.values() {
+                serde_json::to_writer(&mut tmp, &SectorEntry::Sector(record.clone()))?;
+                tmp.write_all(b"\n")?;
+            }
+            for (path, staged) in staged.iter() {
+                let entry = SectorEntry::Staged {
+                    path: path.clone(),
+                    staged: staged.clone(),
+                };
+                serde_json::to_writer(&mut tmp, &entry)?;
+                tmp.write_all(b"\n")?;
+            }
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file,
+            sectors,
+            staged,
+        })
+    }
+
+    pub fn get(&self, prover_id: ProverId, sector_id: SectorId) -> Option<&SectorRecord> {
+        self.sectors.get(&(prover_id, sector_id))
+    }
+
+    /// sectors in `state`, or all of them
+    pub fn list(&self, state: Option<SectorState>) -> Vec<&SectorRecord> {
+        self.sectors
+            .values()
+            .filter(|x| state.map(|s| x.state == s).unwrap_or(true))
+            .collect()
+    }
+
+    /// take in the result of a seal operation, `result` is the `{"Ok": ..}` or `{"Err": ..}` the client gets
+    pub fn observe(&mut self, request: &JobRequest, result: &Value, job: Option<JobToken>, owner: &Option<String>) {
+        let result = match serde_json::from_value::<Result<Value, ApiError>>(result.clone()) {
+            Ok(result) => result.map_err(|e| e.to_string()),
+            Err(e) => Err(format!("unexpected result: {:?}", e)),
+        };
+
+        let key = match request {
+            JobRequest::AddPiece(data) => {
+                let staged = self.staged.entry(data.target.clone()).or_default();
+                // the first piece starts a new staged file
+                if data.piece_lengths.is_empty() {
+                    *staged = Staged::default();
+                }
+
+                match result.and_then(|x| parse::<AddPieceOutput>(x)) {
+                    Ok(output) => {
+                        staged.pieces.push(output.0);
+                        staged
+                            .transitions
+                            .push(Transition::new(SectorState::AddPiece, job, None));
+                    }
+                    Err(e) => staged
+                        .transitions
+                        .push(Transition::new(SectorState::AddPiece, job, Some(e))),
+                }
+
+                let entry = SectorEntry::Staged {
+                    path: data.target.clone(),
+                    staged: staged.clone(),
+                };
+                self.write(&entry);
+                return;
+            }
+            JobRequest::StageSector(data) => {
+                let mut staged = Staged::default();
+                match result.and_then(|x| parse::<StageSectorOutput>(x)) {
+                    Ok(output) => {
+                        staged.pieces = output.piece_infos;
+                        staged
+                            .transitions
+                            .push(Transition::new(SectorState::AddPiece, job, None));
+                    }
+                    Err(e) => staged
+                        .transitions
+                        .push(Transition::new(SectorState::AddPiece, job, Some(e))),
+                }
+
+                let entry = SectorEntry::Staged {
+                    path: data.target.clone(),
+                    staged: staged.clone(),
+                };
+                self.staged.insert(data.target.clone(), staged);
+                self.write(&entry);
+                return;
+            }
+            JobRequest::SealPreCommitPhase1(data) => {
+                let key = (data.prover_id, data.sector_id);
+                let staged = self.staged.remove(&data.in_path);
+                if staged.is_some() {
+                    self.write(&SectorEntry::Unstaged {
+                        path: data.in_path.clone(),
+                    });
+                }
+
+                let record = self
+                    .sectors
+                    .entry(key)
+                    .or_insert_with(|| SectorRecord::new(key.0, key.1, owner));
+                if let Some(staged) = staged {
+                    record.transitions.extend(staged.transitions);
+                }
+                record.proof = Some(format!("{:?}", data.registered_proof));
+                record.staged_path = Some(data.in_path.clone());
+                record.cache_path = Some(data.cache_path.clone());
+                record.sealed_path = Some(data.out_path.clone());
+                record.pieces = data.piece_infos.clone();
+                record.push(SectorState::PreCommit1, job, result.err());
+                key
+            }
+            JobRequest::SealPreCommitPhase2(data) => {
+                // PC2 does not name its sector, it is found by the cache of PC1
+                let record = self
+                    .sectors
+                    .values_mut()
+                    .find(|x| x.cache_path.as_ref() == Some(&data.cache_path));
+                let record = match record {
+                    Some(record) => record,
+                    None => {
+                        warn!("PC2 on unknown sector cache {}", data.cache_path);
+                        return;
+                    }
+                };
+
+                match result.and_then(|x| parse::<SealPreCommitPhase2Output>(x)) {
+                    Ok(output) => {
+                        record.comm_d = Some(output.comm_d);
+                        record.comm_r = Some(output.comm_r);
+                        record.push(SectorState::PreCommit2, job, None);
+                    }
+                    Err(e) => record.push(SectorState::PreCommit2, job, Some(e)),
+                }
+                (record.prover_id, record.sector_id)
+            }
+            JobRequest::SealCommitPhase1(data) => {
+                let key = (data.prover_id, data.sector_id);
+                let record = self
+                    .sectors
+                    .entry(key)
+                    .or_insert_with(|| SectorRecord::new(key.0, key.1, owner));
+                record.cache_path = Some(data.cache_path.clone());
+                record.sealed_path = Some(data.replica_path.clone());
+                record.comm_d = Some(data.pre_commit.comm_d);
+                record.comm_r = Some(data.pre_commit.comm_r);
+                record.push(SectorState::Commit1, job, result.err());
+                key
+            }
+            JobRequest::SealCommitPhase2(data) => {
+                let key = (data.prover_id, data.sector_id);
+                self.sectors
+                    .entry(key)
+                    .or_insert_with(|| SectorRecord::new(key.0, key.1, owner))
+                    .push(SectorState::Commit2, job, result.err());
+                key
+            }
+            _ => return,
+        };
+
+        self.save(key);
+    }
+
+    /// take in the result of `clear_cache` on a sector cache
+    pub fn finalize(&mut self, cache_path: &str, result: &Value) {
+        let error = match serde_json::from_value::<Result<Value, ApiError>>(result.clone()) {
+            Ok(result) => result.err().map(|e| e.to_string()),
+            Err(e) => Some(format!("unexpected result: {:?}", e)),
+        };
+
+        let record = self
+            .sectors
+            .values_mut()
+            .find(|x| x.cache_path.as_ref().map(|x| x.as_str()) == Some(cache_path));
+        if let Some(record) = record {
+            record.push(SectorState::Finalized, None, error);
+            let key = (record.prover_id, record.sector_id);
+            self.save(key);
+        }
+    }
+
+    fn save(&mut self, key: SectorKey) {
+        if let Some(record) = self.sectors.get(&key) {
+            let entry = SectorEntry::Sector(record.clone());
+            self.write(&entry);
+        }
+    }
+
+    fn write(&mut self, entry: &SectorEntry) {
+        let r = serde_json::to_vec(entry).map_err(io::Error::from).and_then(|mut line| {
+            line.push(b'\n');
+            self.file.write_all(&line)
+        });
+
+        if let Err(e) = r {
+            error!("write {:?} failed: {:?}", self.path, e);
+        }
+    }
+}
+
+fn parse<T: DeserializeOwned>(value: Value) -> Result<T, String> {
+    serde_json::from_value(value).map_err(|e| format!("unexpected output: {:?}", e))
+}
+
+/// Query of `/sys/list_sectors`
+#[derive(Deserialize, Debug)]
+pub struct SectorFilter {
+    pub state: Option<SectorState>,
+}
+
+/// Body of `/sys/sector_info`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SectorQuery {
+    pub prover_id: ProverId,
+    pub sector_id: SectorId,
+}
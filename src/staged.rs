@@ -0,0 +1,323 @@
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use filecoin_proofs_api::{seal, UnpaddedBytesAmount};
+use log::warn;
+
+use crate::api_error::{ApiError, ErrorCode};
+use crate::config::CONFIG;
+use crate::polling::JobToken;
+use crate::seal_data::{
+    AddPieceData, AddPieceOutput, StageSectorData, StageSectorOutput, WriteAndPreprocessData, WriteAndPreprocessOutput,
+};
+use crate::types::WebPieceInfo;
+
+/// directory below the state dir for the pipes of `/seal/add_piece_stream`
+const PIPE_DIR: &str = "pipes";
+
+/// One piece written into a staged file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Slot {
+    /// zeros of the given padded size
+    Filler(u64),
+    /// the source piece at this index of the request
+    Source(usize),
+}
+
+/// pieces of a sector holding sources of the given padded sizes in order
+///
+/// Every piece has to start at a multiple of its own size, so the gap before a source is filled with zero pieces,
+/// as is the rest of the sector after the last one. `Err` is the padded size the sources would need.
+pub fn layout(sizes: &[u64], sector_size: u64) -> Result<Vec<Slot>, u64> {
+    let mut slots = vec![];
+    let mut offset = 0;
+    for (n, &size) in sizes.iter().enumerate() {
+        let gap = (size - offset % size) % size;
+        slots.extend(fillers(gap).map(Slot::Filler));
+        slots.push(Slot::Source(n));
+        // piece sizes up to 2^63 pass validation, the sector size check below catches the saturated sum
+        offset = offset.saturating_add(gap + size);
+    }
+
+    if offset > sector_size {
+        return Err(offset);
+    }
+    slots.extend(fillers(sector_size - offset).map(Slot::Filler));
+
+    Ok(slots)
+}
+
+/// power of two pieces covering `gap`, smallest first so each of them is aligned
+fn fillers(gap: u64) -> impl Iterator<Item = u64> {
+    (0..64).map(|n| 1u64 << n).filter(move |x| gap & x != 0)
+}
+
+fn padded(size: UnpaddedBytesAmount) -> u64 {
+    let size = u64::from(size);
+    size + size / 127
+}
+
+fn unpadded(size: u64) -> UnpaddedBytesAmount {
+    UnpaddedBytesAmount(size / 128 * 127)
+}
+
+/// write the sources of `data` into a fresh staged file and compute its comm_d
+pub fn stage_sector(data: &StageSectorData) -> Result<StageSectorOutput, ApiError> {
+    let sector_size = u64::from(data.registered_proof.sector_size());
+    let sizes: Vec<u64> = data.pieces.iter().map(|x| padded(x.piece_size)).collect();
+    let slots = layout(&sizes, sector_size).map_err(|total| {
+        ApiError::new(
+            ErrorCode::InvalidInput,
+            format!("pieces take {} padded bytes, the sector has {}", total, sector_size),
+        )
+    })?;
+
+    let mut target = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&data.target)?;
+
+    let mut piece_infos = vec![];
+    let mut piece_lengths = vec![];
+    let mut sources = vec![];
+    for slot in slots {
+        let (piece_info, _) = match slot {
+            Slot::Filler(size) => {
+                let size = unpadded(size);
+                let zeros = io::repeat(0).take(u64::from(size));
+                seal::add_piece(data.registered_proof, zeros, &mut target, size, &piece_lengths[..])?
+            }
+            Slot::Source(n) => {
+                let piece = &data.pieces[n];
+                // a source shorter than its piece is zero padded
+                let source = File::open(&piece.source)?
+                    .chain(io::repeat(0))
+                    .take(u64::from(piece.piece_size));
+                sources.push(piece_infos.len());
+                seal::add_piece(
+                    data.registered_proof,
+                    source,
+                    &mut target,
+                    piece.piece_size,
+                    &piece_lengths[..],
+                )?
+            }
+        };
+
+        piece_lengths.push(piece_info.size);
+        piece_infos.push(piece_info);
+    }
+
+    let comm_d = seal::compute_comm_d(data.registered_proof, &piece_infos[..])?;
+
+    Ok(StageSectorOutput {
+        piece_infos: piece_infos.into_iter().map(WebPieceInfo::from_object).collect(),
+        sources,
+        comm_d,
+    })
+}
+
+/// Named pipe `/seal/add_piece_stream` passes the request body through to the worker of its job, removed on drop
+pub struct BodyPipe {
+    path: PathBuf,
+}
+
+impl BodyPipe {
+    pub fn create() -> io::Result<Self> {
+        let dir = CONFIG.state_dir.join(PIPE_DIR);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.pipe", JobToken::random()));
+
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// the writing end, blocks until the worker opens the pipe
+    pub fn open(&self) -> io::Result<File> {
+        OpenOptions::new().write(true).open(&self.path)
+    }
+
+    /// let an `open` waiting for a worker that will never come return
+    pub fn release(&self) {
+        let _ = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&self.path);
+    }
+}
+
+impl Drop for BodyPipe {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("remove pipe {:?} failed: {:?}", self.path, e);
+        }
+    }
+}
+
+/// Read of exactly `left` bytes, running out early is an error rather than the end of the data
+struct Exact<R> {
+    inner: R,
+    left: u64,
+}
+
+impl<R: Read> Read for Exact<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.left == 0 {
+            return Ok(0);
+        }
+
+        let max = self.left.min(buf.len() as u64) as usize;
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the piece upload broke off",
+            ));
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+/// run `write` on `path` opened for appending, the file is cut back to its previous length when it fails
+fn append_to<T, W>(path: &str, write: W) -> Result<T, ApiError>
+where
+    W: FnOnce(&mut File) -> anyhow::Result<T>,
+{
+    let mut target = OpenOptions::new().create(true).append(true).open(path)?;
+    let len = target.metadata()?.len();
+
+    write(&mut target).map_err(|e| {
+        if let Err(e) = target.set_len(len) {
+            warn!("truncate {} failed: {:?}", path, e);
+        }
+        ApiError::from(e)
+    })
+}
+
+/// append the piece read from `source` to the staged file
+fn add_piece_from<R: Read>(data: &AddPieceData, source: R) -> Result<AddPieceOutput, ApiError> {
+    // a source shorter than its piece is zero padded
+    let source = source.chain(io::repeat(0)).take(u64::from(data.piece_size));
+    let output = append_to(&data.target, |target| {
+        seal::add_piece(
+            data.registered_proof,
+            source,
+            target,
+            data.piece_size,
+            &data.piece_lengths[..],
+        )
+    })?;
+
+    Ok(AddPieceOutput::from_object(output))
+}
+
+/// `add_piece` of the source file of `data`
+pub fn add_piece(data: &AddPieceData) -> Result<AddPieceOutput, ApiError> {
+    let source = File::open(&data.source)?;
+
+    // a `BodyPipe` always carries the whole piece, the upload behind it failed if it ends early
+    if source.metadata()?.file_type().is_fifo() {
+        let left = u64::from(data.piece_size);
+        return add_piece_from(data, Exact { inner: source, left });
+    }
+
+    add_piece_from(data, source)
+}
+
+/// `write_and_preprocess` of the source file of `data`, appended to the target like a piece
+pub fn write_and_preprocess(data: &WriteAndPreprocessData) -> Result<WriteAndPreprocessOutput, ApiError> {
+    let source = File::open(&data.source)?;
+    let output = append_to(&data.target, |target| {
+        seal::write_and_preprocess(data.registered_proof, source, target, data.piece_size)
+    })?;
+
+    Ok(WriteAndPreprocessOutput::from_object(output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR: u64 = 2048;
+
+    #[test]
+    fn fillers_are_ascending_powers_of_two() {
+        assert_eq!(fillers(0).collect::<Vec<_>>(), Vec::<u64>::new());
+        assert_eq!(fillers(128).collect::<Vec<_>>(), vec![128]);
+        assert_eq!(fillers(384).collect::<Vec<_>>(), vec![128, 256]);
+        assert_eq!(fillers(1920).collect::<Vec<_>>(), vec![128, 256, 512, 1024]);
+    }
+
+    #[test]
+    fn pieces_that_fill_the_sector_need_no_padding() {
+        let slots = layout(&[1024, 512, 512], SECTOR).unwrap();
+        assert_eq!(slots, vec![Slot::Source(0), Slot::Source(1), Slot::Source(2)]);
+    }
+
+    #[test]
+    fn unaligned_piece_is_padded() {
+        // the 512 byte piece can not start at 128
+        let slots = layout(&[128, 512], SECTOR).unwrap();
+        assert_eq!(
+            slots,
+            vec![
+                Slot::Source(0),
+                Slot::Filler(128),
+                Slot::Filler(256),
+                Slot::Source(1),
+                Slot::Filler(1024),
+            ]
+        );
+    }
+
+    #[test]
+    fn every_slot_is_aligned_and_the_sector_is_full() {
+        let sizes = [128, 512, 256, 128];
+        let mut offset = 0;
+        for slot in layout(&sizes, SECTOR).unwrap() {
+            let size = match slot {
+                Slot::Filler(size) => size,
+                Slot::Source(n) => sizes[n],
+            };
+            assert!(size.is_power_of_two());
+            assert_eq!(offset % size, 0, "{:?} at {}", slot, offset);
+            offset += size;
+        }
+        assert_eq!(offset, SECTOR);
+    }
+
+    #[test]
+    fn empty_sector_is_one_filler() {
+        assert_eq!(layout(&[], SECTOR).unwrap(), vec![Slot::Filler(SECTOR)]);
+    }
+
+    #[test]
+    fn oversized_pieces_are_rejected() {
+        assert_eq!(layout(&[4096], SECTOR), Err(4096));
+        // fits by size, but the alignment of the second piece pushes it out
+        assert_eq!(layout(&[128, 1024, 1024], SECTOR), Err(3072));
+        // the sizes add up to the sector, but the 4096 byte piece can not start at 2048
+        assert_eq!(layout(&[2048, 4096, 2048], 4 * SECTOR), Err(10240));
+        assert_eq!(layout(&[1 << 63, 1 << 63], SECTOR), Err(u64::MAX));
+    }
+
+    #[test]
+    fn unpadded_sizes() {
+        assert_eq!(u64::from(unpadded(128)), 127);
+        assert_eq!(padded(UnpaddedBytesAmount(2032)), 2048);
+    }
+}
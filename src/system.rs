@@ -1,17 +1,30 @@
-use std::io::Write;
-use std::sync::mpsc::channel;
-use std::sync::{Arc, Mutex};
-use std::thread::{self, JoinHandle};
-use std::time::Duration;
-
-use actix_multipart::Multipart;
-use actix_web::web::{self, Data, Json};
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_multipart::{Multipart, MultipartError};
+use actix_rt::time;
+use actix_web::web::{self, Bytes, Data, Json, Query};
 use actix_web::{Error, HttpResponse};
-use futures::stream::{StreamExt, TryStreamExt};
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::stream::{self, StreamExt, TryStreamExt};
 use log::trace;
-use serde_json::json;
+use parking_lot::Mutex;
 
+use crate::api_error::{respond, respond_state, ApiError, ErrorCode};
+use crate::auth::ClientId;
+use crate::config::CONFIG;
+use crate::history::{stats, StatsQuery};
+use crate::jobs::JobRequest;
 use crate::polling::*;
+use crate::registry::JobRegistry;
+use crate::sectors::{SectorFilter, SectorQuery, SectorStore};
+
+/// `/sys/wait_job` timeout when the client sets none
+const DEFAULT_WAIT: Duration = Duration::from_secs(60);
+
+/// bytes read from a stored result per chunk of `/sys/download_result`
+const DOWNLOAD_CHUNK: usize = 1 << 20;
 
 pub async fn test() -> HttpResponse {
     trace!("test");
@@ -19,56 +32,281 @@ pub async fn test() -> HttpResponse {
     HttpResponse::Ok().body("Worked!")
 }
 
-pub async fn test_polling(state: Data<Arc<Mutex<ServState>>>) -> HttpResponse {
+pub async fn test_polling(
+    state: Data<Arc<Mutex<ServState>>>,
+    options: Query<JobOptions>,
+    client: ClientId,
+) -> HttpResponse {
     trace!("test polling");
 
-    let (tx, rx) = channel();
-    let handle: JoinHandle<()> = thread::spawn(move || {
-        thread::sleep(Duration::from_secs(30));
-        let r = "Ok!!!";
+    let response = ServState::enqueue(&state, JobRequest::TestPolling(30), options.into_inner(), &client);
+    respond_state(response)
+}
+
+pub async fn query_state(
+    registry: Data<Arc<JobRegistry>>,
+    token: Json<JobToken>,
+    client: ClientId,
+) -> Result<HttpResponse, ApiError> {
+    trace!("query_state");
+
+    let response = registry.get(*token, &client).into_result()?;
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// like `query_state`, but holds the request until the job is no longer queued or running
+pub async fn wait_job(
+    registry: Data<Arc<JobRegistry>>,
+    options: Query<WaitOptions>,
+    client: ClientId,
+) -> Result<HttpResponse, ApiError> {
+    trace!("wait_job: {:?}", options);
+
+    let timeout = options.timeout.map(Duration::from_secs).unwrap_or(DEFAULT_WAIT);
+    let deadline = Instant::now() + timeout.min(CONFIG.max_wait);
+
+    // yields on every transition and ends once the job has left the pending states
+    let mut watcher = registry.watch(options.token, &client);
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match time::timeout(left, watcher.next()).await {
+            Ok(Some(())) => continue,
+            _ => break,
+        }
+    }
+
+    let response = registry.get(options.token, &client).into_result()?;
+
+    Ok(HttpResponse::Ok().json(response))
+}
 
-        tx.send(json!(r)).unwrap();
+struct JobWatch {
+    registry: Arc<JobRegistry>,
+    token: JobToken,
+    client: ClientId,
+    watcher: UnboundedReceiver<()>,
+    /// last event sent
+    last: Option<String>,
+    finished: bool,
+}
+
+/// server-sent events with the state of the job on every transition and progress update
+pub async fn watch_job(
+    registry: Data<Arc<JobRegistry>>,
+    options: Query<WaitOptions>,
+    client: ClientId,
+) -> HttpResponse {
+    trace!("watch_job: {:?}", options);
+
+    let watch = JobWatch {
+        registry: registry.get_ref().clone(),
+        token: options.token,
+        watcher: registry.watch(options.token, &client),
+        client,
+        last: None,
+        finished: false,
+    };
+
+    let events = stream::unfold(watch, |mut watch| async move {
+        while !watch.finished {
+            let current = watch.registry.get(watch.token, &watch.client);
+            watch.finished = match current {
+                PollingState::Queued { .. } | PollingState::Running(_) => false,
+                _ => true,
+            };
+
+            let event = serde_json::to_string(&current).unwrap_or_default();
+            if watch.last.as_ref() != Some(&event) {
+                let data = Bytes::from(format!("data: {}\n\n", event));
+                watch.last = Some(event);
+                return Some((Ok::<_, Error>(data), watch));
+            }
+
+            // running jobs are checked for progress every `watch_interval`
+            let _ = time::timeout(CONFIG.watch_interval, watch.watcher.next()).await;
+        }
+
+        None
     });
 
-    let response = state.lock().unwrap().enqueue(handle, rx);
-    HttpResponse::Ok().json(response)
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .streaming(Box::pin(events))
 }
 
-pub async fn query_state(state: Data<Arc<Mutex<ServState>>>, token: Json<u64>) -> HttpResponse {
-    trace!("query_state");
+/// stream a stored result, the body is the json `Done` would have carried
+pub async fn download_result(
+    registry: Data<Arc<JobRegistry>>,
+    options: Query<DownloadOptions>,
+    client: ClientId,
+) -> Result<HttpResponse, ApiError> {
+    trace!("download_result: {:?}", options);
 
-    let response = state.lock().unwrap().get(*token);
+    let stored = match registry.get(options.id, &client) {
+        PollingState::Stored(stored) => stored,
+        PollingState::Queued { .. } | PollingState::Running(_) => {
+            return Err(ApiError::new(ErrorCode::Conflict, "the job has not finished yet"))
+        }
+        // unknown, expired, failed or small enough to come inline with the job state
+        _ => return Err(ApiError::new(ErrorCode::NotFound, "the job has no stored result")),
+    };
+    let sha256 = stored.sha256.clone();
+    let file = web::block(move || stored.open()).await?;
 
-    HttpResponse::Ok().json(response)
+    // read on the blocking pool one chunk at a time, so memory stays flat for any result size
+    let chunks = stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let r = web::block(move || {
+            let mut chunk = vec![0; DOWNLOAD_CHUNK];
+            let n = file.read(&mut chunk)?;
+            chunk.truncate(n);
+            Ok::<_, io::Error>((file, chunk))
+        })
+        .await;
+
+        match r {
+            Ok((_, chunk)) if chunk.is_empty() => None,
+            Ok((file, chunk)) => Some((Ok(Bytes::from(chunk)), Some(file))),
+            Err(e) => Some((Err(Error::from(e)), None)),
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .header("X-Result-Sha256", sha256)
+        .streaming(Box::pin(chunks)))
 }
 
-pub async fn remove_job(state: Data<Arc<Mutex<ServState>>>, token: Json<u64>) -> HttpResponse {
+pub async fn ack_job(state: Data<Arc<Mutex<ServState>>>, token: Json<JobToken>, client: ClientId) -> HttpResponse {
+    trace!("ack_job");
+
+    let response = state.lock().ack(*token, &client).map_err(ApiError::from);
+
+    respond(response)
+}
+
+pub async fn remove_job(
+    state: Data<Arc<Mutex<ServState>>>,
+    token: Json<JobToken>,
+    client: ClientId,
+) -> Result<HttpResponse, ApiError> {
     trace!("remove_job");
 
-    let response = state.lock().unwrap().remove(*token);
+    let removed = state.lock().remove(*token, &client)?;
+
+    // waiting for the worker to exit must not hold the state lock
+    let response = web::block(move || Ok::<_, ()>(removed.finish()))
+        .await
+        .map_err(|e| PollingError::WorkerFailed(format!("{:?}", e)))?;
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// run a finished or failed job again with its original request, the response carries the new token
+pub async fn retry_job(
+    state: Data<Arc<Mutex<ServState>>>,
+    token: Json<JobToken>,
+    client: ClientId,
+) -> Result<HttpResponse, ApiError> {
+    trace!("retry_job");
+
+    let response = ServState::retry(&state, *token, &client).into_result()?;
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub async fn list_jobs(state: Data<Arc<Mutex<ServState>>>, filter: Json<JobFilter>, client: ClientId) -> HttpResponse {
+    trace!("list_jobs: {:?}", filter);
+
+    let response = state.lock().list(&filter, &client);
+
+    HttpResponse::Ok().json(response)
+}
+
+pub async fn job_detail(state: Data<Arc<Mutex<ServState>>>, token: Json<JobToken>, client: ClientId) -> HttpResponse {
+    trace!("job_detail");
+
+    let response = state
+        .lock()
+        .detail(*token, &client)
+        .ok_or(PollingError::NotExist)
+        .map_err(ApiError::from);
+
+    respond(response)
+}
+
+/// count, duration percentiles and failure rate per operation and proof type of the jobs that ended in a window
+pub async fn job_stats(state: Data<Arc<Mutex<ServState>>>, query: Query<StatsQuery>, client: ClientId) -> HttpResponse {
+    trace!("job_stats: {:?}", query);
+
+    let (from, to) = query.range(unix_now());
+    let history = state.lock().history();
+    let response = web::block(move || history.lock().read(from, to))
+        .await
+        .map(|records| stats(records.iter().filter(|x| client.can_access(&x.owner))))
+        .map_err(ApiError::from);
+
+    respond(response)
+}
+
+/// sectors this server has worked on, `?state=pre_commit2` to list those in one state
+pub async fn list_sectors(
+    sectors: Data<Arc<Mutex<SectorStore>>>,
+    filter: Query<SectorFilter>,
+    client: ClientId,
+) -> HttpResponse {
+    trace!("list_sectors: {:?}", filter);
+
+    let sectors = sectors.lock();
+    let response: Vec<_> = sectors
+        .list(filter.state)
+        .into_iter()
+        .filter(|x| client.can_access(&x.owner))
+        .collect();
 
     HttpResponse::Ok().json(response)
 }
 
-pub async fn upload_file(mut payload: Multipart) -> Result<HttpResponse, Error> {
+pub async fn sector_info(
+    sectors: Data<Arc<Mutex<SectorStore>>>,
+    query: Json<SectorQuery>,
+    client: ClientId,
+) -> HttpResponse {
+    trace!("sector_info: {:?}", query);
+
+    let sectors = sectors.lock();
+    let response = sectors
+        .get(query.prover_id, query.sector_id)
+        .filter(|x| client.can_access(&x.owner))
+        .ok_or_else(|| ApiError::new(ErrorCode::NotFound, "sector does not exist"));
+
+    respond(response)
+}
+
+pub async fn upload_file(mut payload: Multipart) -> Result<HttpResponse, ApiError> {
     trace!("upload_file");
 
     let mut ret_path: Option<String> = None;
 
     // iterate over multipart stream
-    while let Ok(Some(mut field)) = payload.try_next().await {
-        let content_type = field.content_disposition().unwrap();
-        let filename = content_type.get_filename().unwrap();
+    while let Some(mut field) = payload.try_next().await.map_err(invalid_upload)? {
+        let content_type = field
+            .content_disposition()
+            .ok_or_else(|| ApiError::new(ErrorCode::InvalidInput, "upload without content disposition"))?;
+        let filename = content_type
+            .get_filename()
+            .ok_or_else(|| ApiError::new(ErrorCode::InvalidInput, "upload without file name"))?;
         let filepath = format!("/tmp/upload/{}", filename);
         trace!("got file: {}", filepath);
         ret_path = Some(filepath.clone());
 
         // File::create is blocking operation, use threadpool
-        let mut f = web::block(|| std::fs::File::create(filepath)).await.unwrap();
+        let mut f = web::block(|| std::fs::File::create(filepath)).await?;
 
         // Field in turn is stream of *Bytes* object
         while let Some(chunk) = field.next().await {
-            let data = chunk.unwrap();
+            let data = chunk.map_err(invalid_upload)?;
             // filesystem operations are blocking, we have to use threadpool
             f = web::block(move || f.write_all(&data).map(|_| f)).await?;
         }
@@ -78,6 +316,10 @@ pub async fn upload_file(mut payload: Multipart) -> Result<HttpResponse, Error>
     Ok(HttpResponse::Ok().json(ret_path))
 }
 
+fn invalid_upload(e: MultipartError) -> ApiError {
+    ApiError::new(ErrorCode::InvalidInput, e.to_string())
+}
+
 pub async fn upload_test() -> HttpResponse {
     let html = r#"<html>
         <head><title>Upload Test</title></head>
@@ -0,0 +1,664 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use actix_web::HttpResponse;
+use filecoin_proofs_api::seal::VanillaSealProof;
+use filecoin_proofs_api::{RegisteredPoStProof, RegisteredSealProof, UnpaddedBytesAmount};
+use serde::Serialize;
+
+use crate::api_error::ErrorCode;
+use crate::pipeline::PipelineData;
+use crate::post_data::*;
+use crate::seal_data::*;
+use crate::sectors::SectorStore;
+use crate::staged::layout;
+use crate::types::{WebPieceInfo, WebPrivateReplicas, WebPublicReplicas};
+
+/// sector sizes the proofs support
+const SECTOR_SIZES: [u64; 5] = [2 << 10, 8 << 20, 512 << 20, 32 << 30, 64 << 30];
+
+const OVERFLOW: &str = "the sizes add up to more than 2^64 bytes";
+
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    /// path of the field in the request body, e.g. `piece_infos[1].size`
+    pub field: String,
+    pub reason: String,
+}
+
+/// Everything wrong with a request, answered with `422 Unprocessable Entity`
+#[derive(Serialize, Debug)]
+pub struct ValidationErrors {
+    /// always `InvalidInput`
+    pub code: ErrorCode,
+    pub errors: Vec<FieldError>,
+}
+
+impl Default for ValidationErrors {
+    fn default() -> Self {
+        Self {
+            code: ErrorCode::InvalidInput,
+            errors: vec![],
+        }
+    }
+}
+
+impl ValidationErrors {
+    /// a body that does not parse as the request type
+    pub fn body<E: fmt::Display>(e: E) -> Self {
+        let mut errors = Self::default();
+        errors.add("body", e.to_string());
+        errors
+    }
+
+    /// a query string that does not parse as the options of the request
+    pub fn query<E: fmt::Display>(e: E) -> Self {
+        let mut errors = Self::default();
+        errors.add("query", e.to_string());
+        errors
+    }
+
+    fn add<F: Into<String>, R: Into<String>>(&mut self, field: F, reason: R) {
+        self.errors.push(FieldError {
+            field: field.into(),
+            reason: reason.into(),
+        });
+    }
+
+    fn check<F: Into<String>, R: Into<String>>(&mut self, ok: bool, field: F, reason: R) {
+        if !ok {
+            self.add(field, reason);
+        }
+    }
+
+    fn dir(&mut self, field: &str, path: &str) {
+        if !Path::new(path).is_dir() {
+            self.add(field, format!("directory {} does not exist", path));
+        }
+    }
+
+    fn file(&mut self, field: &str, path: &str) {
+        if !Path::new(path).is_file() {
+            self.add(field, format!("file {} does not exist", path));
+        }
+    }
+
+    /// the file may not exist yet, but it must be possible to create it
+    fn new_file(&mut self, field: &str, path: &str) {
+        match Path::new(path).parent() {
+            Some(dir) if dir.as_os_str().is_empty() || dir.is_dir() => {}
+            _ => self.add(field, format!("parent directory of {} does not exist", path)),
+        }
+    }
+
+    /// padded size of the piece, `None` after recording why it is not a valid piece size
+    fn piece_size(&mut self, field: &str, size: UnpaddedBytesAmount) -> Option<u64> {
+        let padded = padded_size(size);
+        if padded.is_none() {
+            self.add(
+                field,
+                format!(
+                    "{} bytes is not a piece size, it must be 127 * 2^n with n >= 0",
+                    u64::from(size)
+                ),
+            );
+        }
+
+        padded
+    }
+
+    /// padded sizes of the pieces, their sum must fit the sector or fill it exactly,
+    /// and each piece must start at a multiple of its size
+    fn pieces(&mut self, field: &str, pieces: &[WebPieceInfo], sector_size: u64, exact: bool) {
+        let mut sizes = vec![];
+        for (n, piece) in pieces.iter().enumerate() {
+            if let Some(padded) = self.piece_size(&format!("{}[{}].size", field, n), piece.size) {
+                sizes.push(padded);
+            }
+        }
+        let total = sizes.iter().try_fold(0u64, |total, x| total.checked_add(*x));
+
+        match total {
+            None => self.add(field, OVERFLOW),
+            Some(total) if exact && total != sector_size => self.add(
+                field,
+                format!("pieces take {} padded bytes, the sector has {}", total, sector_size),
+            ),
+            Some(total) if total > sector_size => self.add(
+                field,
+                format!(
+                    "pieces take {} padded bytes, more than the {} of the sector",
+                    total, sector_size
+                ),
+            ),
+            Some(_) if sizes.len() == pieces.len() => {
+                if let Err(total) = layout(&sizes, sector_size) {
+                    self.add(
+                        field,
+                        format!(
+                            "pieces take {} padded bytes with alignment, the sector has {}",
+                            total, sector_size
+                        ),
+                    );
+                }
+            }
+            Some(_) => {}
+        }
+    }
+
+    /// sources of a staged sector, they must fit the sector together with the padding `layout` puts between them
+    fn stage_pieces(&mut self, field: &str, pieces: &[StagePiece], sector_size: u64) {
+        let mut sizes = vec![];
+        for (n, piece) in pieces.iter().enumerate() {
+            let field = format!("{}[{}]", field, n);
+            match fs::metadata(&piece.source) {
+                Ok(meta) => self.check(
+                    meta.len() <= u64::from(piece.piece_size),
+                    format!("{}.source", field),
+                    format!("source has {} bytes, more than the piece size", meta.len()),
+                ),
+                Err(_) => self.add(
+                    format!("{}.source", field),
+                    format!("file {} does not exist", piece.source),
+                ),
+            }
+            if let Some(padded) = self.piece_size(&format!("{}.piece_size", field), piece.piece_size) {
+                sizes.push(padded);
+            }
+        }
+
+        if let Err(total) = layout(&sizes, sector_size) {
+            self.add(
+                field,
+                format!(
+                    "pieces take {} padded bytes with alignment, the sector has {}",
+                    total, sector_size
+                ),
+            );
+        }
+    }
+
+    /// the `total` padded bytes of a file must fit the sector, `None` if the sum overflowed
+    fn fits(&mut self, field: &str, total: Option<u64>, sector_size: u64) {
+        match total {
+            Some(total) => self.check(
+                total <= sector_size,
+                field,
+                format!("the sector would hold {} padded bytes, it has {}", total, sector_size),
+            ),
+            None => self.add(field, OVERFLOW),
+        }
+    }
+
+    fn result(self) -> Result<(), ValidationErrors> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl From<ValidationErrors> for HttpResponse {
+    fn from(errors: ValidationErrors) -> Self {
+        HttpResponse::UnprocessableEntity().json(errors)
+    }
+}
+
+/// Checks a request before any work starts, so a bad request fails at once instead of deep in the proofs
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationErrors>;
+}
+
+/// `Some` padded size of a valid unpadded piece size
+fn padded_size(size: UnpaddedBytesAmount) -> Option<u64> {
+    let size = u64::from(size);
+    let padded = size.checked_add(size / 127)?;
+    if size >= 127 && size % 127 == 0 && padded.is_power_of_two() {
+        Some(padded)
+    } else {
+        None
+    }
+}
+
+fn sector_size(proof: RegisteredSealProof) -> u64 {
+    u64::from(proof.sector_size())
+}
+
+fn unpadded_sector_size(proof: RegisteredSealProof) -> u64 {
+    sector_size(proof) / 128 * 127
+}
+
+impl Validate for ClearCacheData {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check(
+            SECTOR_SIZES.contains(&self.sector_size),
+            "sector_size",
+            format!("{} is not a sector size", self.sector_size),
+        );
+        errors.dir("cache_path", &self.cache_path);
+
+        errors.result()
+    }
+}
+
+impl Validate for SealPreCommitPhase1Data {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.dir("cache_path", &self.cache_path);
+        match fs::metadata(&self.in_path) {
+            Ok(meta) => errors.check(
+                meta.len() <= unpadded_sector_size(self.registered_proof),
+                "in_path",
+                format!(
+                    "staged file has {} bytes, the sector holds {}",
+                    meta.len(),
+                    unpadded_sector_size(self.registered_proof)
+                ),
+            ),
+            Err(_) => errors.add("in_path", format!("file {} does not exist", self.in_path)),
+        }
+        errors.new_file("out_path", &self.out_path);
+        errors.pieces(
+            "piece_infos",
+            &self.piece_infos,
+            sector_size(self.registered_proof),
+            true,
+        );
+
+        errors.result()
+    }
+}
+
+impl Validate for SealPreCommitPhase2Data {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.dir("cache_path", &self.cache_path);
+        errors.file("out_path", &self.out_path);
+
+        errors.result()
+    }
+}
+
+impl Validate for ComputeCommDData {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.pieces(
+            "piece_infos",
+            &self.piece_infos,
+            sector_size(self.registered_proof),
+            false,
+        );
+
+        errors.result()
+    }
+}
+
+impl Validate for SealCommitPhase1Data {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.dir("cache_path", &self.cache_path);
+        errors.file("replica_path", &self.replica_path);
+        errors.pieces(
+            "piece_infos",
+            &self.piece_infos,
+            sector_size(self.pre_commit.registered_proof),
+            true,
+        );
+
+        errors.result()
+    }
+}
+
+/// the proofs themselves check the rest of the C1 output, after loading the parameters
+impl Validate for SealCommitPhase2Data {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        let output = &self.phase1_output;
+        let partitions = partitions(&output.vanilla_proofs);
+        errors.check(
+            !partitions.is_empty() && partitions.iter().all(|&x| x > 0),
+            "phase1_output.vanilla_proofs",
+            "no vanilla proofs",
+        );
+        errors.check(output.comm_r != [0; 32], "phase1_output.comm_r", "comm_r is zero");
+        errors.check(output.comm_d != [0; 32], "phase1_output.comm_d", "comm_d is zero");
+
+        errors.result()
+    }
+}
+
+/// vanilla proofs per partition of a C1 output
+fn partitions(proofs: &VanillaSealProof) -> Vec<usize> {
+    match proofs {
+        VanillaSealProof::StackedDrg2KiBV1(x) => x.iter().map(Vec::len).collect(),
+        VanillaSealProof::StackedDrg8MiBV1(x) => x.iter().map(Vec::len).collect(),
+        VanillaSealProof::StackedDrg512MiBV1(x) => x.iter().map(Vec::len).collect(),
+        VanillaSealProof::StackedDrg32GiBV1(x) => x.iter().map(Vec::len).collect(),
+        VanillaSealProof::StackedDrg64GiBV1(x) => x.iter().map(Vec::len).collect(),
+    }
+}
+
+/// the C1 output has to carry the commitments recorded for the sector, if it was sealed here
+pub fn commit_sector(data: &SealCommitPhase2Data, sectors: &SectorStore) -> Result<(), ValidationErrors> {
+    let mut errors = ValidationErrors::default();
+    if let Some(sector) = sectors.get(data.prover_id, data.sector_id) {
+        let output = &data.phase1_output;
+        let sector_id = u64::from(data.sector_id);
+        errors.check(
+            sector.comm_r.map(|x| x == output.comm_r).unwrap_or(true),
+            "phase1_output.comm_r",
+            format!("comm_r is not the one of sector {} of this prover", sector_id),
+        );
+        errors.check(
+            sector.comm_d.map(|x| x == output.comm_d).unwrap_or(true),
+            "phase1_output.comm_d",
+            format!("comm_d is not the one of sector {} of this prover", sector_id),
+        );
+    }
+
+    errors.result()
+}
+
+impl Validate for VerifySealData {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check(!self.proof_vec.is_empty(), "proof_vec", "the proof is empty");
+
+        errors.result()
+    }
+}
+
+impl Validate for VerifyBatchSealData {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        let count = self.proof_vecs.len();
+        let lens = [
+            ("comm_r_ins", self.comm_r_ins.len()),
+            ("comm_d_ins", self.comm_d_ins.len()),
+            ("prover_ids", self.prover_ids.len()),
+            ("sector_ids", self.sector_ids.len()),
+            ("tickets", self.tickets.len()),
+            ("seeds", self.seeds.len()),
+        ];
+        for (field, len) in lens.iter() {
+            errors.check(
+                *len == count,
+                *field,
+                format!("has {} entries for {} proofs", len, count),
+            );
+        }
+        for (n, proof) in self.proof_vecs.iter().enumerate() {
+            errors.check(!proof.is_empty(), format!("proof_vecs[{}]", n), "the proof is empty");
+        }
+
+        errors.result()
+    }
+}
+
+impl Validate for GetUnsealedRangeData {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.dir("cache_path", &self.cache_path);
+        errors.file("sealed_path", &self.sealed_path);
+        errors.new_file("output_path", &self.output_path);
+
+        let size = unpadded_sector_size(self.registered_proof);
+        match u64::from(self.offset).checked_add(u64::from(self.num_bytes)) {
+            Some(end) => errors.check(
+                end <= size,
+                "num_bytes",
+                format!("range ends at {}, after the {} bytes of the sector", end, size),
+            ),
+            None => errors.add("num_bytes", OVERFLOW),
+        }
+
+        errors.result()
+    }
+}
+
+impl Validate for GeneratePieceCommitmentData {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.file("source", &self.source);
+        if let Some(padded) = errors.piece_size("piece_size", self.piece_size) {
+            errors.check(
+                padded <= sector_size(self.registered_proof),
+                "piece_size",
+                "the piece is larger than the sector",
+            );
+        }
+
+        errors.result()
+    }
+}
+
+impl Validate for AddPieceData {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.file("source", &self.source);
+        errors.new_file("target", &self.target);
+
+        let mut total = Some(errors.piece_size("piece_size", self.piece_size).unwrap_or(0));
+        for (n, size) in self.piece_lengths.iter().enumerate() {
+            let padded = errors.piece_size(&format!("piece_lengths[{}]", n), *size).unwrap_or(0);
+            total = total.and_then(|x| x.checked_add(padded));
+        }
+        errors.fits("piece_size", total, sector_size(self.registered_proof));
+
+        errors.result()
+    }
+}
+
+impl Validate for AddPieceStreamQuery {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.new_file("target", &self.target);
+
+        let mut total = Some(errors.piece_size("piece_size", self.piece_size).unwrap_or(0));
+        match self.piece_lengths() {
+            Ok(lengths) => {
+                for (n, size) in lengths.into_iter().enumerate() {
+                    let padded = errors.piece_size(&format!("piece_lengths[{}]", n), size).unwrap_or(0);
+                    total = total.and_then(|x| x.checked_add(padded));
+                }
+            }
+            Err(e) => errors.add("piece_lengths", format!("not a comma separated list of sizes: {}", e)),
+        }
+        errors.fits("piece_size", total, sector_size(self.registered_proof));
+
+        errors.result()
+    }
+}
+
+impl Validate for WriteAndPreprocessData {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.file("source", &self.source);
+        errors.new_file("target", &self.target);
+        if let Some(padded) = errors.piece_size("piece_size", self.piece_size) {
+            errors.check(
+                padded <= sector_size(self.registered_proof),
+                "piece_size",
+                "the piece is larger than the sector",
+            );
+        }
+
+        errors.result()
+    }
+}
+
+impl Validate for StageSectorData {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.new_file("target", &self.target);
+        errors.check(!self.pieces.is_empty(), "pieces", "no pieces");
+        errors.stage_pieces("pieces", &self.pieces, sector_size(self.registered_proof));
+
+        errors.result()
+    }
+}
+
+impl Validate for PipelineData {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        let size = sector_size(self.registered_proof);
+        if self.pieces.is_empty() {
+            errors.file("staged_path", &self.staged_path);
+            errors.pieces("piece_infos", &self.piece_infos, size, true);
+        } else {
+            errors.new_file("staged_path", &self.staged_path);
+            errors.stage_pieces("pieces", &self.pieces, size);
+        }
+        errors.dir("cache_path", &self.cache_path);
+        errors.new_file("sealed_path", &self.sealed_path);
+
+        errors.result()
+    }
+}
+
+impl Validate for GenerateWinningPostSectorChallengeData {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check(
+            is_winning(self.proof_type),
+            "proof_type",
+            format!("{:?} is not a winning PoSt proof", self.proof_type),
+        );
+        errors.check(self.sector_set_len > 0, "sector_set_len", "the sector set is empty");
+
+        errors.result()
+    }
+}
+
+/// also checks `GenerateWindowPostData`, the kind of proof is checked by `post_kind`
+impl Validate for GenerateWinningPostData {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        private_replicas(&mut errors, &self.replicas);
+
+        errors.result()
+    }
+}
+
+impl Validate for VerifyWinningPostData {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check(!self.proof.is_empty(), "proof", "the proof is empty");
+        public_replicas(&mut errors, &self.replicas, true);
+
+        errors.result()
+    }
+}
+
+impl Validate for VerifyWindowPostData {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check(!self.proof.is_empty(), "proof", "the proof is empty");
+        public_replicas(&mut errors, &self.replicas, false);
+
+        let replica_proof = self.replicas.0.first().map(|x| x.public_replica_info.registered_proof);
+        for (n, (proof, _)) in self.proof.iter().enumerate() {
+            errors.check(
+                replica_proof.map(|x| x == *proof).unwrap_or(true),
+                format!("proof[{}]", n),
+                format!("{:?} does not match the proof of the replicas", proof),
+            );
+        }
+
+        errors.result()
+    }
+}
+
+/// check that all replicas of a PoSt request use a winning or a window PoSt proof
+pub fn post_kind(data: &GenerateWinningPostData, winning: bool) -> Result<(), ValidationErrors> {
+    let mut errors = ValidationErrors::default();
+    for (n, replica) in data.replicas.0.iter().enumerate() {
+        let proof = replica.private_replica_info.registered_proof;
+        errors.check(
+            is_winning(proof) == winning,
+            format!("replicas[{}].private_replica_info.registered_proof", n),
+            format!(
+                "{:?} is not a {} PoSt proof",
+                proof,
+                if winning { "winning" } else { "window" }
+            ),
+        );
+    }
+
+    errors.result()
+}
+
+fn is_winning(proof: RegisteredPoStProof) -> bool {
+    match proof {
+        RegisteredPoStProof::StackedDrgWinning2KiBV1
+        | RegisteredPoStProof::StackedDrgWinning8MiBV1
+        | RegisteredPoStProof::StackedDrgWinning512MiBV1
+        | RegisteredPoStProof::StackedDrgWinning32GiBV1
+        | RegisteredPoStProof::StackedDrgWinning64GiBV1 => true,
+        RegisteredPoStProof::StackedDrgWindow2KiBV1
+        | RegisteredPoStProof::StackedDrgWindow8MiBV1
+        | RegisteredPoStProof::StackedDrgWindow512MiBV1
+        | RegisteredPoStProof::StackedDrgWindow32GiBV1
+        | RegisteredPoStProof::StackedDrgWindow64GiBV1 => false,
+    }
+}
+
+fn private_replicas(errors: &mut ValidationErrors, replicas: &WebPrivateReplicas) {
+    errors.check(!replicas.0.is_empty(), "replicas", "no replicas");
+
+    let first = replicas.0.first().map(|x| x.private_replica_info.registered_proof);
+    let mut sector_ids = HashSet::new();
+    for (n, replica) in replicas.0.iter().enumerate() {
+        let field = format!("replicas[{}]", n);
+        let info = &replica.private_replica_info;
+        errors.check(
+            sector_ids.insert(replica.sector_id),
+            format!("{}.sector_id", field),
+            "the sector is listed twice",
+        );
+        errors.check(
+            Some(info.registered_proof) == first,
+            format!("{}.private_replica_info.registered_proof", field),
+            "the replicas use different proofs",
+        );
+        errors.dir(&format!("{}.private_replica_info.cache_dir", field), &info.cache_dir);
+        errors.file(
+            &format!("{}.private_replica_info.replica_path", field),
+            &info.replica_path,
+        );
+    }
+}
+
+fn public_replicas(errors: &mut ValidationErrors, replicas: &WebPublicReplicas, winning: bool) {
+    errors.check(!replicas.0.is_empty(), "replicas", "no replicas");
+
+    let first = replicas.0.first().map(|x| x.public_replica_info.registered_proof);
+    let mut sector_ids = HashSet::new();
+    for (n, replica) in replicas.0.iter().enumerate() {
+        let field = format!("replicas[{}]", n);
+        let proof = replica.public_replica_info.registered_proof;
+        errors.check(
+            sector_ids.insert(replica.sector_id),
+            format!("{}.sector_id", field),
+            "the sector is listed twice",
+        );
+        errors.check(
+            Some(proof) == first,
+            format!("{}.public_replica_info.registered_proof", field),
+            "the replicas use different proofs",
+        );
+        errors.check(
+            is_winning(proof) == winning,
+            format!("{}.public_replica_info.registered_proof", field),
+            format!(
+                "{:?} is not a {} PoSt proof",
+                proof,
+                if winning { "winning" } else { "window" }
+            ),
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use actix_rt::time::delay_for;
+use awc::Client;
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::StreamExt;
+use log::{info, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::config::CONFIG;
+use crate::polling::{unix_now, JobToken, PollingState, ServState};
+
+pub type DeliverySender = UnboundedSender<Delivery>;
+pub type DeliveryReceiver = UnboundedReceiver<Delivery>;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum CallbackStatus {
+    Pending,
+    Delivered {
+        attempts: u32,
+        time: u64,
+    },
+    /// gave up after `attempts`, `error` is the last failure
+    Failed {
+        attempts: u32,
+        error: String,
+    },
+}
+
+/// final state of a job to be posted to its callback url
+#[derive(Debug)]
+pub struct Delivery {
+    pub token: JobToken,
+    pub url: String,
+    pub state: PollingState,
+}
+
+/// post the final state of finished jobs to their callback url
+pub fn spawn_webhook(state: Arc<Mutex<ServState>>) -> JoinHandle<()> {
+    let mut deliveries = state.lock().take_deliveries().expect("webhook already started");
+
+    thread::spawn(move || {
+        let mut sys = actix_rt::System::new("webhook");
+        sys.block_on(async move {
+            let client = Client::build().timeout(CONFIG.callback_timeout).finish();
+            while let Some(delivery) = deliveries.next().await {
+                actix_rt::spawn(deliver(client.clone(), state.clone(), delivery));
+            }
+        });
+    })
+}
+
+async fn deliver(client: Client, state: Arc<Mutex<ServState>>, delivery: Delivery) {
+    let body = json!({
+        "token": delivery.token,
+        "state": delivery.state,
+    });
+
+    let mut attempts = 0;
+    let status = loop {
+        attempts += 1;
+        let r = match client.post(&delivery.url).send_json(&body).await {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => Err(format!("status {}", response.status())),
+            Err(e) => Err(format!("{:?}", e)),
+        };
+
+        match r {
+            Ok(()) => {
+                break CallbackStatus::Delivered {
+                    attempts,
+                    time: unix_now(),
+                }
+            }
+            Err(error) if attempts > CONFIG.callback_retries => break CallbackStatus::Failed { attempts, error },
+            Err(error) => {
+                // 1s, 2s, 4s, ... between attempts
+                let backoff = Duration::from_secs(1 << (attempts - 1).min(10));
+                warn!(
+                    "callback of job {} to {} failed: {}, retry in {:?}",
+                    delivery.token, delivery.url, error, backoff
+                );
+                delay_for(backoff).await;
+            }
+        }
+    };
+
+    info!("callback of job {}: {:?}", delivery.token, status);
+    state.lock().set_callback(delivery.token, status);
+}